@@ -1,77 +1,1100 @@
+use crate::dismissals::{get_dismissed_bands, get_dismissed_items};
+use crate::freshness::{self, DAY};
 use crate::items::get_item;
-use crate::types::Item;
+use crate::recommenders::{Recommender, Strategy};
+use crate::types::{Explanation, FeedEntry, Item, ItemType, RecommendationPage};
 use crate::{DbPoolSnafu, DbPrepareSnafu, DbReadSnafu, Error, NotFoundSnafu};
 use fallible_iterator::FallibleIterator;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::Connection;
+use serde::Deserialize;
 use snafu::{OptionExt, ResultExt};
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+// One row per item of every collector sharing at least two items with the user
 const SELECT_RELEVANT_USERS: &str = r#"
-select fan_id, group_concat(item_id) from collects
+select fan_id, item_id from collects
 where fan_id in (
     select fan_id from collects
     where item_id in (
         select item_id from collects
         where fan_id = (
-            select fan_id from collector where username = ?
+            select fan_id from collector where username = ?1
+        )
+        union all
+        select item_id from wishes
+        where ?2 and fan_id = (
+            select fan_id from collector where username = ?1
         )
     )
-	group by fan_id
+    group by fan_id
     having count(fan_id) > 1
-)
-group by fan_id"#;
+)"#;
 
-fn get_relevant_users(db: &Connection, name: &str) -> Result<HashMap<i64, HashSet<i64>>, Error> {
+pub fn get_relevant_users(
+    db: &Connection,
+    name: &str,
+    with_wishlist: bool,
+) -> Result<HashMap<i64, HashSet<i64>>, Error> {
     let mut stmt = db
         .prepare_cached(SELECT_RELEVANT_USERS)
         .context(DbPrepareSnafu)?;
+    let mut rows = stmt.query((name, with_wishlist)).context(DbReadSnafu)?;
+    let mut result: HashMap<i64, HashSet<i64>> = HashMap::new();
+    while let Some(row) = rows.next().context(DbReadSnafu)? {
+        let fan_id = row.get(0).context(DbReadSnafu)?;
+        let item_id = row.get(1).context(DbReadSnafu)?;
+        result.entry(fan_id).or_default().insert(item_id);
+    }
+    Ok(result)
+}
+
+const SELECT_ALSO_COLLECTED_COUNT: &str = r#"
+select also_collected_count from item where item_id = ?"#;
+
+fn get_also_collected_count(db: &Connection, item_id: i64) -> Result<i64, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_ALSO_COLLECTED_COUNT)
+        .context(DbPrepareSnafu)?;
     let result = stmt
-        .query([name])
+        .query_row([item_id], |row| row.get(0))
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+// popularity_penalty of 0 is the plain overlap score, 1 divides by the full popularity
+fn apply_popularity_penalty(
+    db: &Connection,
+    count: &mut HashMap<i64, f64>,
+    collection: &HashSet<i64>,
+    neighbours: &HashMap<i64, HashSet<i64>>,
+    popularity_penalty: f64,
+) -> Result<(), Error> {
+    let mut owners: HashMap<i64, i64> = HashMap::new();
+    for user in neighbours.values() {
+        for item in user.difference(collection) {
+            *owners.entry(*item).or_default() += 1;
+        }
+    }
+    for (item_id, score) in count.iter_mut() {
+        let popularity = get_also_collected_count(db, *item_id)?
+            .max(owners.get(item_id).copied().unwrap_or(0))
+            .max(1);
+        *score /= (popularity as f64).powf(popularity_penalty);
+    }
+    Ok(())
+}
+
+pub fn rank_candidates(
+    db: &Connection,
+    fan_id: i64,
+    collection: &HashSet<i64>,
+    neighbours: &HashMap<i64, HashSet<i64>>,
+    recommender: &dyn Recommender,
+    popularity_penalty: f64,
+) -> Result<Vec<(i64, f64)>, Error> {
+    let mut count = recommender.score(db, fan_id, collection, neighbours)?;
+    if popularity_penalty > 0.0 {
+        apply_popularity_penalty(db, &mut count, collection, neighbours, popularity_penalty)?;
+    }
+    let mut elements = count.into_iter().collect::<Vec<_>>();
+    elements.sort_unstable_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    Ok(elements)
+}
+
+const EXPLANATION_SIZE: usize = 3;
+
+fn explain(
+    db: &Connection,
+    item_id: i64,
+    collection: &HashSet<i64>,
+    neighbours: &HashMap<i64, HashSet<i64>>,
+) -> Result<Explanation, Error> {
+    let mut contributors = neighbours
+        .iter()
+        .filter(|(_, user)| user.contains(&item_id))
+        .map(|(fan_id, user)| (*fan_id, user.intersection(collection).count()))
+        .collect::<Vec<_>>();
+    contributors.sort_unstable_by(|(a_id, a), (b_id, b)| b.cmp(a).then(a_id.cmp(b_id)));
+    contributors.truncate(EXPLANATION_SIZE);
+    let mut shared: HashMap<i64, usize> = HashMap::new();
+    for (fan_id, _) in &contributors {
+        for item in neighbours[fan_id].intersection(collection) {
+            *shared.entry(*item).or_default() += 1;
+        }
+    }
+    let mut shared = shared.into_iter().collect::<Vec<_>>();
+    shared.sort_unstable_by(|(a_id, a), (b_id, b)| b.cmp(a).then(a_id.cmp(b_id)));
+    let mut explanation = Explanation {
+        collectors: Vec::new(),
+        items: Vec::new(),
+    };
+    for (fan_id, _) in contributors {
+        if let Some(username) = crate::collectors::get_username_for_fan_id(db, fan_id)? {
+            explanation.collectors.push(username);
+        }
+    }
+    for (item_id, _) in shared.into_iter().take(EXPLANATION_SIZE) {
+        explanation.items.push(get_item(db, item_id)?.item_title);
+    }
+    Ok(explanation)
+}
+
+const SELECT_SHARED_COOCCURRENCES: &str = r#"
+select other_id from item_cooccurrence
+where item_id = ?1 and other_id in (select item_id from collects where fan_id = ?2)
+order by count desc
+limit ?3"#;
+
+// Read from collects, like the co-occurrence counts themselves
+const SELECT_COLLECTORS_OF_BOTH: &str = r#"
+select username from collects c1
+join collects c2 using (fan_id)
+join collector using (fan_id)
+where c1.item_id = ?1 and c2.item_id = ?2 and fan_id != ?3
+limit ?4"#;
+
+fn explain_from_cooccurrence(
+    db: &Connection,
+    fan_id: i64,
+    item_id: i64,
+) -> Result<Explanation, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_SHARED_COOCCURRENCES)
+        .context(DbPrepareSnafu)?;
+    let shared = stmt
+        .query((item_id, fan_id, EXPLANATION_SIZE))
         .context(DbReadSnafu)?
-        .map(|r| {
-            Ok((
-                r.get(0)?,
-                r.get::<_, String>(1)?
-                    .split(',')
-                    .map(|v| v.parse().unwrap())
-                    .collect(),
-            ))
-        })
+        .map(|r| r.get(0))
+        .collect::<Vec<i64>>()
+        .context(DbReadSnafu)?;
+    let mut explanation = Explanation {
+        collectors: Vec::new(),
+        items: Vec::new(),
+    };
+    if let Some(strongest) = shared.first() {
+        let mut stmt = db
+            .prepare_cached(SELECT_COLLECTORS_OF_BOTH)
+            .context(DbPrepareSnafu)?;
+        explanation.collectors = stmt
+            .query((strongest, item_id, fan_id, EXPLANATION_SIZE))
+            .context(DbReadSnafu)?
+            .map(|r| r.get(0))
+            .collect()
+            .context(DbReadSnafu)?;
+    }
+    for item_id in shared {
+        explanation.items.push(get_item(db, item_id)?.item_title);
+    }
+    Ok(explanation)
+}
+
+const SELECT_COLLECTION: &str = r#"
+select item_id from collects where fan_id = ?"#;
+
+fn get_collection(db: &Connection, fan_id: i64) -> Result<HashSet<i64>, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_COLLECTION)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query([fan_id])
+        .context(DbReadSnafu)?
+        .map(|r| r.get(0))
+        .collect()
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WishlistMode {
+    #[default]
+    Ignore,
+    /// Treat wishlist items like collected ones
+    Seed,
+    Exclude,
+    /// Exclude them, but return them ranked in a separate list
+    Separate,
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct RecommendationFilter {
+    pub item_types: Option<HashSet<ItemType>>,
+    pub exclude_known_bands: bool,
+    pub max_per_band: Option<usize>,
+    pub wishlist: WishlistMode,
+}
+
+const SELECT_WISHLIST: &str = r#"
+select item_id from wishes where fan_id = ?"#;
+
+fn get_wishlist(db: &Connection, fan_id: i64) -> Result<HashSet<i64>, Error> {
+    let mut stmt = db.prepare_cached(SELECT_WISHLIST).context(DbPrepareSnafu)?;
+    let result = stmt
+        .query([fan_id])
+        .context(DbReadSnafu)?
+        .map(|r| r.get(0))
+        .collect()
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+const SELECT_KNOWN_BANDS: &str = r#"
+select distinct band_id from item
+where item_id in (select item_id from collects where fan_id = ?)"#;
+
+fn get_known_bands(db: &Connection, fan_id: i64) -> Result<HashSet<i64>, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_KNOWN_BANDS)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query([fan_id])
+        .context(DbReadSnafu)?
+        .map(|r| r.get(0))
         .collect()
         .context(DbReadSnafu)?;
     Ok(result)
 }
 
+// What the filters need to know about a candidate, so they don't have to load every item
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    item_type: ItemType,
+    band_id: i64,
+}
+
+const SELECT_CANDIDATE: &str = r#"
+select item_type, band_id from item where item_id = ?"#;
+
+fn get_candidates(
+    db: &Connection,
+    elements: &[(i64, f64)],
+) -> Result<HashMap<i64, Candidate>, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_CANDIDATE)
+        .context(DbPrepareSnafu)?;
+    let mut result = HashMap::new();
+    for (item_id, _) in elements {
+        let candidate = stmt
+            .query([item_id])
+            .context(DbReadSnafu)?
+            .next()
+            .context(DbReadSnafu)?
+            .map(|row| {
+                Ok(Candidate {
+                    item_type: row.get(0)?,
+                    band_id: row.get(1)?,
+                })
+            })
+            .transpose()
+            .context(DbReadSnafu)?;
+        if let Some(candidate) = candidate {
+            result.insert(*item_id, candidate);
+        }
+    }
+    Ok(result)
+}
+
+// Drops the ranked candidates the filter excludes, keeping their order.
+// Dismissals are read on every call, so they apply right away
+fn filter_candidates(
+    db: &Connection,
+    fan_ids: &[i64],
+    elements: &[(i64, f64)],
+    candidates: &HashMap<i64, Candidate>,
+    filter: &RecommendationFilter,
+) -> Result<Vec<(i64, f64)>, Error> {
+    let mut excluded_bands = HashSet::new();
+    let mut excluded_items = HashSet::new();
+    for fan_id in fan_ids {
+        if filter.exclude_known_bands {
+            excluded_bands.extend(get_known_bands(db, *fan_id)?);
+        }
+        excluded_bands.extend(get_dismissed_bands(db, *fan_id)?);
+        excluded_items.extend(get_dismissed_items(db, *fan_id)?);
+        if matches!(
+            filter.wishlist,
+            WishlistMode::Exclude | WishlistMode::Separate
+        ) {
+            excluded_items.extend(get_wishlist(db, *fan_id)?);
+        }
+    }
+    let mut per_band: HashMap<i64, usize> = HashMap::new();
+    let mut result = Vec::new();
+    for (item_id, score) in elements {
+        if excluded_items.contains(item_id) {
+            continue;
+        }
+        let Some(candidate) = candidates.get(item_id) else {
+            continue;
+        };
+        let wrong_type = filter
+            .item_types
+            .as_ref()
+            .is_some_and(|item_types| !item_types.contains(&candidate.item_type));
+        if wrong_type || excluded_bands.contains(&candidate.band_id) {
+            continue;
+        }
+        let band_count = per_band.entry(candidate.band_id).or_default();
+        if filter.max_per_band.is_some_and(|max| *band_count >= max) {
+            continue;
+        }
+        *band_count += 1;
+        result.push((*item_id, *score));
+    }
+    Ok(result)
+}
+
+// Only the items of the requested page are loaded
+fn get_page(
+    db: &Connection,
+    elements: &[(i64, f64)],
+    offset: usize,
+    limit: usize,
+) -> Result<Vec<Item>, Error> {
+    let mut result = Vec::new();
+    for (item_id, score) in elements.iter().skip(offset).take(limit) {
+        let mut item = get_item(db, *item_id)?;
+        item.score = Some(*score);
+        result.push(item);
+    }
+    Ok(result)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RankingParams {
+    pub strategy: Strategy,
+    pub similar_boost: u64,
+    pub popularity_penalty: u64,
+    pub seed_wishlist: bool,
+}
+
+impl RankingParams {
+    // similar_boost is clamped to 1..=5 and popularity_penalty to 0..=1, wherever they come from
+    pub fn new(
+        strategy: Strategy,
+        similar_boost: f64,
+        popularity_penalty: f64,
+        seed_wishlist: bool,
+    ) -> Self {
+        let similar_boost = similar_boost.clamp(1.0, 5.0);
+        let popularity_penalty = popularity_penalty.clamp(0.0, 1.0);
+        RankingParams {
+            strategy,
+            similar_boost: similar_boost.to_bits(),
+            popularity_penalty: popularity_penalty.to_bits(),
+            seed_wishlist,
+        }
+    }
+
+    pub fn recommender(&self) -> Box<dyn Recommender> {
+        self.strategy.recommender(
+            f64::from_bits(self.similar_boost),
+            f64::from_bits(self.popularity_penalty),
+        )
+    }
+}
+
+struct Ranking {
+    fan_id: i64,
+    collection: HashSet<i64>,
+    neighbours: HashMap<i64, HashSet<i64>>,
+    elements: Vec<(i64, f64)>,
+    candidates: HashMap<i64, Candidate>,
+}
+
+const RANKING_CACHE_SIZE: usize = 16;
+const RANKING_CACHE_TTL: Duration = Duration::from_secs(600);
+
+type RankingKey = (String, RankingParams);
+
+// Keeps the full ranking of recent requests, so further pages don't rerun the analysis
+#[derive(Default)]
+pub struct RankingCache {
+    entries: Mutex<HashMap<RankingKey, (Instant, Arc<Ranking>)>>,
+}
+
+impl RankingCache {
+    fn get(&self, username: &str, params: &RankingParams) -> Option<Arc<Ranking>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (created, _)| created.elapsed() < RANKING_CACHE_TTL);
+        entries
+            .get(&(username.to_string(), *params))
+            .map(|(_, ranking)| ranking.clone())
+    }
+
+    fn insert(&self, username: &str, params: &RankingParams, ranking: Arc<Ranking>) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= RANKING_CACHE_SIZE {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (created, _))| *created)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert((username.to_string(), *params), (Instant::now(), ranking));
+    }
+}
+
+fn get_ranking(db: &Connection, username: &str, params: &RankingParams) -> Result<Ranking, Error> {
+    let fan_id =
+        crate::collectors::get_fan_id_for_username(db, username)?.context(NotFoundSnafu)?;
+    let recommender = params.recommender();
+    let (mut collection, neighbours) = if recommender.needs_neighbours() {
+        let mut users = get_relevant_users(db, username, params.seed_wishlist)?;
+        (users.remove(&fan_id).unwrap_or_default(), users)
+    } else {
+        (get_collection(db, fan_id)?, HashMap::new())
+    };
+    if params.seed_wishlist {
+        collection.extend(get_wishlist(db, fan_id)?);
+    }
+    let elements = rank_candidates(
+        db,
+        fan_id,
+        &collection,
+        &neighbours,
+        recommender.as_ref(),
+        f64::from_bits(params.popularity_penalty),
+    )?;
+    let candidates = get_candidates(db, &elements)?;
+    Ok(Ranking {
+        fan_id,
+        collection,
+        neighbours,
+        elements,
+        candidates,
+    })
+}
+
+fn get_cached_ranking(
+    db: &Connection,
+    cache: &RankingCache,
+    username: &str,
+    params: &RankingParams,
+) -> Result<Arc<Ranking>, Error> {
+    if let Some(ranking) = cache.get(username, params) {
+        return Ok(ranking);
+    }
+    let ranking = Arc::new(get_ranking(db, username, params)?);
+    cache.insert(username, params, ranking.clone());
+    Ok(ranking)
+}
+
+// Wishlist items without any endorsement from the neighbourhood are ranked last
+fn get_ranked_wishlist(db: &Connection, ranking: &Ranking) -> Result<Vec<Item>, Error> {
+    let mut wishlist = get_wishlist(db, ranking.fan_id)?;
+    let mut result = Vec::new();
+    for (item_id, score) in &ranking.elements {
+        if wishlist.remove(item_id) {
+            let mut item = get_item(db, *item_id)?;
+            item.score = Some(*score);
+            result.push(item);
+        }
+    }
+    let mut remaining = wishlist.into_iter().collect::<Vec<_>>();
+    remaining.sort_unstable();
+    for item_id in remaining {
+        let mut item = get_item(db, item_id)?;
+        item.score = Some(0.0);
+        result.push(item);
+    }
+    Ok(result)
+}
+
+fn explain_ranking(
+    db: &Connection,
+    ranking: &Ranking,
+    params: &RankingParams,
+    item_id: i64,
+) -> Result<Explanation, Error> {
+    if params.recommender().needs_neighbours() {
+        explain(db, item_id, &ranking.collection, &ranking.neighbours)
+    } else {
+        explain_from_cooccurrence(db, ranking.fan_id, item_id)
+    }
+}
+
 pub fn get_user_recommendations(
     db: &Pool<SqliteConnectionManager>,
+    cache: &RankingCache,
     username: &str,
-    similar_boost: f64,
-) -> Result<Vec<Item>, Error> {
+    params: &RankingParams,
+    filter: &RecommendationFilter,
+    offset: usize,
+    limit: usize,
+) -> Result<RecommendationPage, Error> {
+    let conn = db.get().context(DbPoolSnafu)?;
+    let ranking = get_cached_ranking(&conn, cache, username, params)?;
+    let elements = filter_candidates(
+        &conn,
+        &[ranking.fan_id],
+        &ranking.elements,
+        &ranking.candidates,
+        filter,
+    )?;
+    let mut items = get_page(&conn, &elements, offset, limit)?;
+    for item in items.iter_mut() {
+        item.explanation = Some(explain_ranking(&conn, &ranking, params, item.item_id)?);
+    }
+    let wishlist = if filter.wishlist == WishlistMode::Separate {
+        Some(get_ranked_wishlist(&conn, &ranking)?)
+    } else {
+        None
+    };
+    Ok(RecommendationPage {
+        total: elements.len(),
+        offset,
+        items,
+        wishlist,
+    })
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    #[default]
+    Sum,
+    LeastMisery,
+    Average,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn get_group_recommendations(
+    db: &Pool<SqliteConnectionManager>,
+    cache: &RankingCache,
+    usernames: &[String],
+    params: &RankingParams,
+    aggregation: Aggregation,
+    filter: &RecommendationFilter,
+    offset: usize,
+    limit: usize,
+) -> Result<RecommendationPage, Error> {
+    let conn = db.get().context(DbPoolSnafu)?;
+    let mut rankings = Vec::new();
+    for username in usernames {
+        rankings.push(get_cached_ranking(&conn, cache, username, params)?);
+    }
+    let mut owned = HashSet::new();
+    for ranking in &rankings {
+        owned.extend(ranking.collection.iter().copied());
+    }
+    // scores of different members are on different scales, so each is normalized to 0..=1 first.
+    // None for members whose ranking doesn't contain the item
+    let mut scores: HashMap<i64, Vec<Option<f64>>> = HashMap::new();
+    for (index, ranking) in rankings.iter().enumerate() {
+        let max = ranking.elements.first().map(|(_, score)| *score);
+        for (item_id, score) in &ranking.elements {
+            if owned.contains(item_id) {
+                continue;
+            }
+            let entry = scores
+                .entry(*item_id)
+                .or_insert_with(|| vec![None; rankings.len()]);
+            entry[index] = Some(score / max.unwrap_or(1.0));
+        }
+    }
+    let mut elements = scores
+        .iter()
+        .map(|(item_id, member_scores)| {
+            let scored = member_scores.iter().flatten();
+            let score = match aggregation {
+                Aggregation::Sum => scored.sum(),
+                // only over the members who scored it, otherwise it orders like the sum
+                Aggregation::Average => scored.clone().sum::<f64>() / scored.count() as f64,
+                Aggregation::LeastMisery => member_scores
+                    .iter()
+                    .map(|score| score.unwrap_or(0.0))
+                    .fold(f64::MAX, f64::min),
+            };
+            (*item_id, score)
+        })
+        .filter(|(_, score)| *score > 0.0)
+        .collect::<Vec<_>>();
+    elements.sort_unstable_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    let fan_ids = rankings
+        .iter()
+        .map(|ranking| ranking.fan_id)
+        .collect::<Vec<_>>();
+    let mut candidates = HashMap::new();
+    for ranking in &rankings {
+        candidates.extend(ranking.candidates.iter().map(|(id, c)| (*id, *c)));
+    }
+    let elements = filter_candidates(&conn, &fan_ids, &elements, &candidates, filter)?;
+    let mut items = get_page(&conn, &elements, offset, limit)?;
+    for item in items.iter_mut() {
+        // explained from the perspective of the member who likes it the most
+        let member_scores = &scores[&item.item_id];
+        let best = (0..rankings.len())
+            .max_by(|a, b| {
+                member_scores[*a]
+                    .unwrap_or(0.0)
+                    .partial_cmp(&member_scores[*b].unwrap_or(0.0))
+                    .unwrap_or(Ordering::Equal)
+            })
+            .unwrap_or(0);
+        item.explanation = Some(explain_ranking(
+            &conn,
+            &rankings[best],
+            params,
+            item.item_id,
+        )?);
+    }
+    Ok(RecommendationPage {
+        total: elements.len(),
+        offset,
+        items,
+        wishlist: None,
+    })
+}
+
+// Only the most similar neighbours contribute to the feed, weighted like the overlap strategy
+const FEED_NEIGHBOURS: usize = 50;
+const FEED_SIMILAR_BOOST: f64 = 2.0;
+
+// Without a purchase date, items from the first fetch of a collection say nothing about
+// when they were bought
+const SELECT_RECENT_PURCHASES: &str = r#"
+select item_id, coalesce(purchased, first_seen) as purchased_at from collection_history
+where fan_id = ?1 and removed is null and purchased_at >= ?2 and (
+    purchased is not null or
+    first_seen > (select min(first_seen) from collection_history where fan_id = ?1)
+)"#;
+
+fn get_recent_purchases(
+    db: &Connection,
+    fan_id: i64,
+    since: i64,
+) -> Result<Vec<(i64, i64)>, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_RECENT_PURCHASES)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query((fan_id, since))
+        .context(DbReadSnafu)?
+        .map(|r| Ok((r.get(0)?, r.get(1)?)))
+        .collect()
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+// What the most similar fans bought in the last `days` days, newer purchases count more
+pub fn get_user_feed(
+    db: &Pool<SqliteConnectionManager>,
+    username: &str,
+    days: i64,
+    limit: usize,
+) -> Result<Vec<FeedEntry>, Error> {
     let conn = db.get().context(DbPoolSnafu)?;
     let fan_id =
         crate::collectors::get_fan_id_for_username(&conn, username)?.context(NotFoundSnafu)?;
-    let users = get_relevant_users(&conn, username)?;
-    let forbidden = users[&fan_id].clone();
-    let mut count: HashMap<i64, f64> = HashMap::new();
-    for (_, user) in users {
-        let mult = (user.intersection(&forbidden).count() as f64).powf(similar_boost);
-        if mult > 1.0 {
-            for item in user.difference(&forbidden) {
-                let entry = count.entry(*item).or_default();
-                *entry += mult;
+    let mut users = get_relevant_users(&conn, username, false)?;
+    let collection = users.remove(&fan_id).unwrap_or_default();
+    let mut similarity = users
+        .iter()
+        .map(|(fan_id, user)| (*fan_id, user.intersection(&collection).count()))
+        .collect::<Vec<_>>();
+    similarity.sort_unstable_by(|(a_id, a), (b_id, b)| b.cmp(a).then(a_id.cmp(b_id)));
+    similarity.truncate(FEED_NEIGHBOURS);
+    let now = freshness::now();
+    let window = (days * DAY).max(1);
+    let mut scores: HashMap<i64, f64> = HashMap::new();
+    let mut latest: HashMap<i64, i64> = HashMap::new();
+    for (neighbour, overlap) in &similarity {
+        let weight = (*overlap as f64).powf(FEED_SIMILAR_BOOST);
+        for (item_id, purchased_at) in get_recent_purchases(&conn, *neighbour, now - window)? {
+            if collection.contains(&item_id) {
+                continue;
             }
+            // fades out linearly over the window
+            let recency = 1.0 - ((now - purchased_at).max(0) as f64 / window as f64);
+            *scores.entry(item_id).or_default() += weight * recency;
+            let last = latest.entry(item_id).or_default();
+            *last = (*last).max(purchased_at);
         }
     }
-    let mut elements = count.into_iter().collect::<Vec<_>>();
-    elements.sort_unstable_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    let mut elements = scores.into_iter().collect::<Vec<_>>();
+    elements.sort_unstable_by(|(a_id, a), (b_id, b)| {
+        b.partial_cmp(a)
+            .unwrap_or(Ordering::Equal)
+            .then(a_id.cmp(b_id))
+    });
+    let candidates = get_candidates(&conn, &elements)?;
+    let filter = RecommendationFilter::default();
+    let elements = filter_candidates(&conn, &[fan_id], &elements, &candidates, &filter)?;
+    let items = get_page(&conn, &elements, 0, limit)?;
+    let neighbours = similarity
+        .iter()
+        .filter_map(|(fan_id, _)| users.remove_entry(fan_id))
+        .collect::<HashMap<_, _>>();
+    let mut result = Vec::new();
+    for mut item in items {
+        item.explanation = Some(explain(&conn, item.item_id, &collection, &neighbours)?);
+        result.push(FeedEntry {
+            purchased_at: latest[&item.item_id],
+            item,
+        });
+    }
+    Ok(result)
+}
+
+const SELECT_CO_COLLECTED_ITEMS: &str = r#"
+select item_id, count(*) as overlap from collects
+where fan_id in (
+    select fan_id from collected_by where item_id = ?1
+    union
+    select fan_id from collects where item_id = ?1
+) and item_id != ?1
+group by item_id
+order by overlap desc
+limit 50"#;
+
+pub fn get_similar_items(
+    db: &Pool<SqliteConnectionManager>,
+    item_id: i64,
+) -> Result<Vec<Item>, Error> {
+    let conn = db.get().context(DbPoolSnafu)?;
+    // make sure the item itself is known
+    get_item(&conn, item_id).map_err(|err| match err {
+        Error::DbResultError => Error::NotFoundError,
+        err => err,
+    })?;
+    let mut stmt = conn
+        .prepare_cached(SELECT_CO_COLLECTED_ITEMS)
+        .context(DbPrepareSnafu)?;
+    let elements = stmt
+        .query([item_id])
+        .context(DbReadSnafu)?
+        .map(|r| Ok((r.get(0)?, r.get(1)?)))
+        .collect::<Vec<(i64, f64)>>()
+        .context(DbReadSnafu)?;
     let mut result = Vec::new();
-    for (item_id, score) in elements.into_iter().take(50) {
+    for (item_id, score) in elements {
         let mut item = get_item(&conn, item_id)?;
         item.score = Some(score);
         result.push(item)
     }
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::test_pool;
+
+    fn collect(conn: &Connection, fan_id: i64, items: impl IntoIterator<Item = i64>) {
+        conn.execute(
+            "insert or ignore into collector values (?1, 'fan' || ?1, '', null, 0)",
+            [fan_id],
+        )
+        .unwrap();
+        for item_id in items {
+            conn.execute(
+                "insert or ignore into item
+                values (?1, 'album', 'Item ' || ?1, '', ?1, 'Band', null, 0, 0)",
+                [item_id],
+            )
+            .unwrap();
+            conn.execute("insert into collects values (?, ?)", [fan_id, item_id])
+                .unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "workers")]
+    fn explains_with_neighbours_and_shared_items() {
+        let store = test_pool("analyze_explain");
+        let mut conn = store.get().unwrap();
+        collect(&conn, 1, [1, 2, 3]);
+        collect(&conn, 2, [1, 2, 10]);
+        collect(&conn, 3, [1, 2, 3, 10]);
+        collect(&conn, 4, [1, 2, 3, 11]);
+        for fan_id in 1..=4 {
+            crate::cooccurrence::sync_fan(&mut conn, fan_id).unwrap();
+        }
+        let cache = RankingCache::default();
+        let explain_top = |strategy| {
+            let params = RankingParams::new(strategy, 2.0, 0.0, false);
+            let filter = RecommendationFilter::default();
+            let page =
+                get_user_recommendations(&store, &cache, "fan1", &params, &filter, 0, 1).unwrap();
+            assert_eq!(page.items[0].item_id, 10);
+            page.items[0].explanation.clone().unwrap()
+        };
+        // the most similar contributor first, then the items shared by most contributors
+        let explanation = explain_top(Strategy::Overlap);
+        assert_eq!(explanation.collectors, ["fan3", "fan2"]);
+        assert_eq!(explanation.items, ["Item 1", "Item 2", "Item 3"]);
+        let explanation = explain_top(Strategy::Item);
+        let collectors = explanation.collectors.into_iter().collect::<HashSet<_>>();
+        assert_eq!(
+            collectors,
+            HashSet::from(["fan2".to_string(), "fan3".to_string()])
+        );
+        assert_eq!(explanation.items.len(), 3);
+    }
+
+    #[test]
+    fn clamps_ranking_params() {
+        assert_eq!(
+            RankingParams::new(Strategy::Overlap, 10.0, -1.0, false),
+            RankingParams::new(Strategy::Overlap, 5.0, 0.0, false)
+        );
+        assert_eq!(
+            RankingParams::new(Strategy::Overlap, 0.0, 2.0, false),
+            RankingParams::new(Strategy::Overlap, 1.0, 1.0, false)
+        );
+    }
+
+    #[test]
+    fn item_strategy_applies_popularity_penalty() {
+        let store = test_pool("analyze_penalty");
+        let conn = store.get().unwrap();
+        conn.execute_batch(
+            "insert into collector values (1, 'a', 'A', null, 0), (2, 'b', 'B', null, 0);
+            insert into item values (1, 'album', 'One', '', 1, 'Band', null, 4, 0);
+            insert into item values (2, 'album', 'Two', '', 1, 'Band', null, 4, 0);
+            insert into collects values (1, 1), (2, 1), (2, 2);
+            insert into cooccurrence_collects values (1, 1), (2, 1), (2, 2);
+            insert into item_cooccurrence values (1, 2, 1), (2, 1, 1);
+            insert into item_occurrence values (1, 2), (2, 1);",
+        )
+        .unwrap();
+        // item based rankings have no neighbours to count owners from
+        let params = RankingParams::new(Strategy::Item, 2.0, 0.5, false);
+        let page = get_user_recommendations(
+            &store,
+            &RankingCache::default(),
+            "a",
+            &params,
+            &RecommendationFilter::default(),
+            0,
+            10,
+        )
+        .unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].item_id, 2);
+        assert_eq!(page.items[0].score, Some(0.5));
+    }
+
+    #[test]
+    fn filters_types_and_bands_before_the_cut() {
+        let store = test_pool("analyze_filters");
+        let conn = store.get().unwrap();
+        collect(&conn, 1, [1, 2, 3]);
+        collect(&conn, 2, (1..=3).chain(10..=15));
+        conn.execute_batch(
+            "update item set item_type = 'track' where item_id = 10;
+            update item set band_id = 100 where item_id in (11, 12, 13);
+            update item set band_id = 1 where item_id = 14;",
+        )
+        .unwrap();
+        let params = RankingParams::new(Strategy::Overlap, 2.0, 0.0, false);
+        let cache = RankingCache::default();
+        let recommend = |filter: RecommendationFilter, limit| {
+            let page = get_user_recommendations(&store, &cache, "fan1", &params, &filter, 0, limit)
+                .unwrap();
+            let items = page.items.iter().map(|item| item.item_id);
+            (page.total, items.collect::<HashSet<_>>())
+        };
+        let (total, items) = recommend(
+            RecommendationFilter {
+                item_types: Some(HashSet::from([ItemType::Album])),
+                ..Default::default()
+            },
+            10,
+        );
+        assert_eq!(total, 5);
+        assert!(!items.contains(&10));
+        let (total, items) = recommend(
+            RecommendationFilter {
+                exclude_known_bands: true,
+                ..Default::default()
+            },
+            10,
+        );
+        assert_eq!(total, 5);
+        assert!(!items.contains(&14));
+        let (total, items) = recommend(
+            RecommendationFilter {
+                max_per_band: Some(1),
+                ..Default::default()
+            },
+            10,
+        );
+        assert_eq!(total, 4);
+        assert_eq!(items.iter().filter(|id| (11..=13).contains(*id)).count(), 1);
+        // still a full page after everything is filtered
+        let (total, items) = recommend(
+            RecommendationFilter {
+                item_types: Some(HashSet::from([ItemType::Album])),
+                exclude_known_bands: true,
+                max_per_band: Some(1),
+                ..Default::default()
+            },
+            2,
+        );
+        assert_eq!(total, 2);
+        assert_eq!(items.len(), 2);
+        assert!(items.contains(&15));
+    }
+
+    #[test]
+    fn pages_count_only_filtered_candidates() {
+        let store = test_pool("analyze_pages");
+        let conn = store.get().unwrap();
+        collect(&conn, 1, 1..=3);
+        collect(&conn, 2, (1..=3).chain(10..=13));
+        conn.execute_batch(
+            "update item set item_type = 'track' where item_id = 13;
+            insert into dismissed_item values (1, 10);",
+        )
+        .unwrap();
+        let params = RankingParams::new(Strategy::Overlap, 2.0, 0.0, false);
+        let cache = RankingCache::default();
+        let filter = RecommendationFilter {
+            item_types: Some(HashSet::from([ItemType::Album])),
+            ..Default::default()
+        };
+        let mut seen = HashSet::new();
+        for offset in 0..3 {
+            let page =
+                get_user_recommendations(&store, &cache, "fan1", &params, &filter, offset, 1)
+                    .unwrap();
+            assert_eq!(page.total, 2);
+            seen.extend(page.items.iter().map(|item| item.item_id));
+        }
+        assert_eq!(seen, HashSet::from([11, 12]));
+    }
+
+    #[test]
+    fn aggregations_rank_differently() {
+        let store = test_pool("analyze_aggregation");
+        let conn = store.get().unwrap();
+        let (a, b, c, e) = (1001, 1002, 1003, 1004);
+        // the first member's neighbours: raw scores a 18, b 12, c 20, normalized 0.9, 0.6, 1.0
+        collect(&conn, 1, 1..=9);
+        collect(&conn, 11, (1..=9).chain([a, c]));
+        collect(&conn, 12, (1..=9).chain([a, c]));
+        collect(&conn, 13, [1, 2, b, c]);
+        collect(&conn, 14, (1..=5).chain([b]));
+        collect(&conn, 15, (1..=5).chain([b]));
+        // the second member's: raw scores b 10, c 6, e 20, normalized 0.5, 0.3, 1.0
+        collect(&conn, 2, 101..=110);
+        collect(&conn, 21, (101..=110).chain([e]));
+        collect(&conn, 22, (101..=110).chain([e]));
+        collect(&conn, 23, (101..=110).chain([b]));
+        collect(&conn, 24, (101..=106).chain([c]));
+        let usernames = ["fan1".to_string(), "fan2".to_string()];
+        let params = RankingParams::new(Strategy::Overlap, 1.0, 0.0, false);
+        let cache = RankingCache::default();
+        let top = |aggregation| {
+            let page = get_group_recommendations(
+                &store,
+                &cache,
+                &usernames,
+                &params,
+                aggregation,
+                &RecommendationFilter::default(),
+                0,
+                10,
+            )
+            .unwrap();
+            page.items[0].item_id
+        };
+        // sums 1.3 for c, averages 1.0 for e, and c is only 0.3 for the second member
+        assert_eq!(top(Aggregation::Sum), c);
+        assert_eq!(top(Aggregation::Average), e);
+        assert_eq!(top(Aggregation::LeastMisery), b);
+    }
+
+    #[test]
+    fn groups_skip_what_any_member_owns_or_dismissed() {
+        let store = test_pool("analyze_group");
+        let conn = store.get().unwrap();
+        collect(&conn, 1, [1, 2, 3]);
+        collect(&conn, 2, [4, 5, 6, 20]);
+        collect(&conn, 3, [1, 2, 3, 20, 21]);
+        collect(&conn, 4, [4, 5, 6, 22]);
+        let usernames = ["fan1".to_string(), "fan2".to_string()];
+        let params = RankingParams::new(Strategy::Overlap, 2.0, 0.0, false);
+        let cache = RankingCache::default();
+        let recommend = || {
+            get_group_recommendations(
+                &store,
+                &cache,
+                &usernames,
+                &params,
+                Aggregation::Sum,
+                &RecommendationFilter::default(),
+                0,
+                10,
+            )
+            .unwrap()
+        };
+        let page = recommend();
+        assert_eq!(page.total, 2);
+        let item = page.items.iter().find(|item| item.item_id == 21).unwrap();
+        // explained from the side of the member it was found for
+        assert_eq!(item.explanation.as_ref().unwrap().collectors, ["fan3"]);
+        conn.execute("insert into dismissed_item values (2, 22)", [])
+            .unwrap();
+        let page = recommend();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.items[0].item_id, 21);
+    }
+
+    #[test]
+    fn wishlist_modes() {
+        let store = test_pool("analyze_wishlist");
+        let conn = store.get().unwrap();
+        collect(&conn, 1, [1, 2]);
+        collect(&conn, 2, [1, 2, 3, 10]);
+        collect(&conn, 3, [2, 3, 11]);
+        conn.execute_batch(
+            "insert into item values (30, 'album', 'Item 30', '', 30, 'Band', null, 0, 0);
+            insert into wishes values (1, 3), (1, 30);",
+        )
+        .unwrap();
+        let cache = RankingCache::default();
+        let recommend = |wishlist| {
+            let params =
+                RankingParams::new(Strategy::Overlap, 2.0, 0.0, wishlist == WishlistMode::Seed);
+            let filter = RecommendationFilter {
+                wishlist,
+                ..Default::default()
+            };
+            let page =
+                get_user_recommendations(&store, &cache, "fan1", &params, &filter, 0, 10).unwrap();
+            let items = page.items.iter().map(|item| item.item_id).collect();
+            let wishlist = page
+                .wishlist
+                .map(|items| items.iter().map(|item| item.item_id).collect::<Vec<_>>());
+            (items, wishlist)
+        };
+        assert_eq!(
+            recommend(WishlistMode::Ignore),
+            (HashSet::from([3, 10]), None)
+        );
+        assert_eq!(
+            recommend(WishlistMode::Exclude),
+            (HashSet::from([10]), None)
+        );
+        // endorsed wishlist items first, the rest after them
+        assert_eq!(
+            recommend(WishlistMode::Separate),
+            (HashSet::from([10]), Some(vec![3, 30]))
+        );
+        // the wishlist makes the third fan a neighbour
+        assert_eq!(
+            recommend(WishlistMode::Seed),
+            (HashSet::from([10, 11]), None)
+        );
+    }
+}
@@ -1,4 +1,8 @@
-use clap::Parser;
+use bandcamp_recommendations::{
+    EvaluationConfig, ExportFormat, ExportTable, FreshnessPolicy, Strategy, DAY, DEFAULT_BASE_URL,
+    DEFAULT_BURST, DEFAULT_REQUESTS_PER_SECOND,
+};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
@@ -8,11 +12,263 @@ pub struct Args {
     #[clap(long, short)]
     pub database: PathBuf,
 
+    #[clap(flatten)]
+    pub freshness: FreshnessArgs,
+
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Serve the web interface, fetching whatever users ask for
+    Serve(ServeArgs),
+    /// Crawl all of bandcamp without serving anything
+    Crawl(ClientArgs),
+    /// Print recommendations for a collector already in the database
+    Recommend(RecommendArgs),
+    /// Print statistics about the database
+    Stats(StatsArgs),
+    /// Export a table as json lines, csv or parquet
+    Export(ExportArgs),
+    /// Import a table written by export
+    Import(ImportArgs),
+    /// Evaluate recommendation quality on the database
+    Evaluate(EvaluationArgs),
+    /// Update the database schema, every other command does this implicitly
+    Migrate(MigrateArgs),
+}
+
+#[derive(clap::Args)]
+pub struct ServeArgs {
     /// Listen address
     #[clap(long, short)]
     pub address: SocketAddr,
 
-    /// Crawl all of bandcamp
+    /// Crawl all of bandcamp while serving
     #[clap(long, short)]
     pub crawl: bool,
+
+    /// Only serve what is in the database, without workers and endpoints that write
+    #[clap(long, conflicts_with = "crawl")]
+    pub read_only: bool,
+
+    /// Enables the admin endpoints for requests sending it as `Authorization: Bearer <token>`
+    #[clap(long)]
+    pub admin_token: Option<String>,
+
+    /// Secret mixed into the hashes of anonymized exports, random for every start by default
+    #[clap(long, requires = "admin_token")]
+    pub export_salt: Option<String>,
+
+    #[clap(flatten)]
+    pub client: ClientArgs,
+}
+
+#[derive(clap::Args)]
+pub struct ClientArgs {
+    /// Base url of the bandcamp site to scrape
+    #[clap(long, default_value = DEFAULT_BASE_URL)]
+    pub base_url: String,
+
+    /// Maximum requests per second sent to bandcamp, lowered automatically when rate limited
+    #[clap(long, default_value_t = DEFAULT_REQUESTS_PER_SECOND, value_parser = positive_rate)]
+    pub requests_per_second: f64,
+
+    /// Number of requests that may be sent at once after being idle
+    #[clap(long, default_value_t = DEFAULT_BURST)]
+    pub burst: u32,
+}
+
+// clap's ranges only work for integers
+fn positive_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        Ok(_) => Err("must be greater than 0".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+}
+
+#[derive(clap::Args)]
+pub struct RecommendArgs {
+    pub username: String,
+
+    #[clap(long, value_enum, default_value_t)]
+    pub strategy: Strategy,
+
+    /// Exponent applied to the similarity of each collector, between 1 and 5
+    #[clap(long, default_value_t = 2.0)]
+    pub similar_boost: f64,
+
+    /// How strongly popular items are penalized, between 0 and 1
+    #[clap(long, default_value_t = 0.0)]
+    pub popularity_penalty: f64,
+
+    /// Skip bands the collector already owns something of
+    #[clap(long)]
+    pub exclude_known_bands: bool,
+
+    #[clap(long, default_value_t = 20)]
+    pub limit: usize,
+
+    #[clap(long, default_value_t = 0)]
+    pub offset: usize,
+
+    #[clap(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+}
+
+#[derive(clap::Args)]
+pub struct StatsArgs {
+    #[clap(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+}
+
+#[derive(clap::Args)]
+pub struct ExportArgs {
+    /// Table to export
+    #[clap(value_enum)]
+    pub table: ExportTable,
+
+    /// File to write to instead of stdout
+    #[clap(long, short)]
+    pub output: Option<PathBuf>,
+
+    #[clap(long, value_enum, default_value_t)]
+    pub format: ExportFormat,
+
+    /// Hash usernames and fan ids, drop display names and tokens
+    #[clap(long, requires = "salt")]
+    pub anonymize: bool,
+
+    /// Secret mixed into the hashes, use the same one for every table of an export
+    #[clap(long, requires = "anonymize")]
+    pub salt: Option<String>,
+}
+
+#[derive(clap::Args)]
+pub struct ImportArgs {
+    /// Table to import, collectors and items have to be imported before the tables referencing them
+    #[clap(value_enum)]
+    pub table: ExportTable,
+
+    /// File written by export
+    pub input: PathBuf,
+
+    #[clap(long, value_enum, default_value_t)]
+    pub format: ExportFormat,
+}
+
+#[derive(clap::Args)]
+pub struct MigrateArgs {
+    /// Only list the migrations that would be applied
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(clap::Args)]
+pub struct FreshnessArgs {
+    /// Days after which a collection is fetched again
+    #[clap(long, global = true, default_value_t = 30, value_parser = positive_days)]
+    pub collection_max_age: i64,
+
+    /// Days after which the collection of an active collector is fetched again
+    #[clap(long, global = true, default_value_t = 7, value_parser = positive_days)]
+    pub active_collection_max_age: i64,
+
+    /// New items between two fetches for a collector to count as active
+    #[clap(long, global = true, default_value_t = 5)]
+    pub active_new_items: i64,
+
+    /// Days after which the collectors of an item are fetched again
+    #[clap(long, global = true, default_value_t = 30, value_parser = positive_days)]
+    pub collected_by_max_age: i64,
+}
+
+fn positive_days(value: &str) -> Result<i64, String> {
+    match value.parse::<i64>() {
+        Ok(days) if days > 0 => Ok(days),
+        Ok(_) => Err("must be at least 1".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+impl FreshnessArgs {
+    pub fn policy(&self) -> FreshnessPolicy {
+        FreshnessPolicy {
+            collection_max_age: self.collection_max_age * DAY,
+            active_collection_max_age: self.active_collection_max_age * DAY,
+            active_new_items: self.active_new_items,
+            collected_by_max_age: self.collected_by_max_age * DAY,
+        }
+    }
+}
+
+#[derive(clap::Args)]
+pub struct EvaluationArgs {
+    /// Number of collectors to sample
+    #[clap(long, default_value_t = 100)]
+    pub users: usize,
+
+    /// Fraction of each sampled collection to hide
+    #[clap(long, default_value_t = 0.2, value_parser = fraction)]
+    pub hidden_fraction: f64,
+
+    /// Number of recommendations to score
+    #[clap(long, short, default_value_t = 50, value_parser = positive_count)]
+    pub k: usize,
+
+    /// Seed for sampling collectors and hidden items
+    #[clap(long, default_value_t = 0)]
+    pub seed: u64,
+
+    /// Recommendation strategy to evaluate
+    #[clap(long, value_enum, default_value_t)]
+    pub strategy: Strategy,
+
+    /// Exponent applied to the similarity of each collector
+    #[clap(long, default_value_t = 2.0)]
+    pub similar_boost: f64,
+
+    /// How strongly popular items are penalized
+    #[clap(long, default_value_t = 0.0)]
+    pub popularity_penalty: f64,
+}
+
+fn positive_count(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        Ok(_) => Err("must be at least 1".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+// Strictly between 0 and 1, so something is hidden and something is left to recommend from
+fn fraction(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(fraction) if fraction > 0.0 && fraction < 1.0 => Ok(fraction),
+        Ok(_) => Err("must be between 0 and 1".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+impl EvaluationArgs {
+    pub fn config(&self) -> EvaluationConfig {
+        EvaluationConfig {
+            users: self.users,
+            hidden_fraction: self.hidden_fraction,
+            k: self.k,
+            seed: self.seed,
+            strategy: self.strategy,
+            similar_boost: self.similar_boost,
+            popularity_penalty: self.popularity_penalty,
+        }
+    }
 }
@@ -1,29 +1,49 @@
-use crate::types::{Collector, Item};
-use crate::{
-    DbPoolSnafu, DbPrepareSnafu, DbReadSnafu, DbWriteSnafu, Error, NetworkSnafu, PageSnafu,
-    SerializationSnafu,
-};
+#[cfg(feature = "workers")]
+use crate::client::BandcampClient;
+use crate::types::{purchase_from_row, Purchase};
+#[cfg(feature = "workers")]
+use crate::types::{Collector, Item, JobKind};
+#[cfg(feature = "workers")]
+use crate::{freshness, jobs, DbPoolSnafu, DbWriteSnafu, PageSnafu, SerializationSnafu};
+use crate::{DbPrepareSnafu, DbReadSnafu, Error};
+#[cfg(feature = "workers")]
+use chrono::NaiveDateTime;
 use fallible_iterator::FallibleIterator;
+#[cfg(feature = "workers")]
 use r2d2::Pool;
+#[cfg(feature = "workers")]
 use r2d2_sqlite::SqliteConnectionManager;
-use reqwest::{Client, StatusCode};
 use rusqlite::Connection;
+#[cfg(feature = "workers")]
+use rusqlite::{OptionalExtension, TransactionBehavior};
+#[cfg(feature = "workers")]
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "workers")]
 use serde_json::json;
-use snafu::{OptionExt, ResultExt};
+#[cfg(feature = "workers")]
+use snafu::OptionExt;
+use snafu::ResultExt;
+#[cfg(feature = "workers")]
 use soup::{NodeExt, QueryBuilderExt, Soup};
-use std::collections::HashMap;
+#[cfg(feature = "workers")]
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "workers")]
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+#[cfg(feature = "workers")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "workers")]
 use tokio::task::spawn_blocking;
-use tokio::time::{interval, sleep, MissedTickBehavior};
+#[cfg(feature = "workers")]
+use tokio::time::sleep;
 
+#[cfg(feature = "workers")]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct CollectionResult {
     pub items: Vec<Item>,
     pub more_available: bool,
 }
 
+#[cfg(feature = "workers")]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct CollectionData {
     pub last_token: Option<String>,
@@ -31,21 +51,193 @@ struct CollectionData {
     pub batch_size: i64,
 }
 
+#[cfg(feature = "workers")]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct ItemCache {
     pub collection: HashMap<String, Item>,
+    #[serde(default)]
+    pub wishlist: HashMap<String, Item>,
 }
 
+#[cfg(feature = "workers")]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct InitialResult {
     pub fan_data: Collector,
     pub collection_data: CollectionData,
+    pub wishlist_data: Option<CollectionData>,
     pub item_cache: ItemCache,
 }
+
+#[cfg(feature = "workers")]
+#[derive(Debug, Clone, Copy)]
+enum CollectionKind {
+    Collection,
+    Wishlist,
+}
+
+#[cfg(feature = "workers")]
+impl CollectionKind {
+    fn path(self) -> &'static str {
+        match self {
+            CollectionKind::Collection => "/api/fancollection/1/collection_items",
+            CollectionKind::Wishlist => "/api/fancollection/1/wishlist_items",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            CollectionKind::Collection => "collection",
+            CollectionKind::Wishlist => "wishlist",
+        }
+    }
+
+    // Returns the item id and whether it was part of the collection already
+    fn add_item(
+        self,
+        db: &Connection,
+        fan_id: i64,
+        item: &Item,
+        now: i64,
+    ) -> Result<(i64, bool), Error> {
+        match self {
+            CollectionKind::Collection => add_item_for_collector(db, fan_id, item, now),
+            CollectionKind::Wishlist => add_item_for_wishlist(db, fan_id, item),
+        }
+    }
+
+    fn get_item_ids(self, db: &Connection, fan_id: i64) -> Result<Vec<i64>, Error> {
+        let query = match self {
+            CollectionKind::Collection => SELECT_COLLECTS,
+            CollectionKind::Wishlist => SELECT_WISHES,
+        };
+        let mut stmt = db.prepare_cached(query).context(DbPrepareSnafu)?;
+        let result = stmt
+            .query([fan_id])
+            .context(DbReadSnafu)?
+            .map(|row| row.get(0))
+            .collect()
+            .context(DbReadSnafu)?;
+        Ok(result)
+    }
+
+    fn remove_item(
+        self,
+        db: &Connection,
+        fan_id: i64,
+        item_id: i64,
+        now: i64,
+    ) -> Result<(), Error> {
+        match self {
+            CollectionKind::Collection => remove_item_for_collector(db, fan_id, item_id, now),
+            CollectionKind::Wishlist => {
+                let mut stmt = db.prepare_cached(DELETE_WISH).context(DbPrepareSnafu)?;
+                stmt.execute((fan_id, item_id)).context(DbWriteSnafu)?;
+                Ok(())
+            }
+        }
+    }
+
+    // Whether a matching item count is enough to tell that nothing was removed
+    fn recently_walked(self, db: &Connection, fan_id: i64, now: i64) -> Result<bool, Error> {
+        match self {
+            CollectionKind::Collection => {
+                let mut stmt = db
+                    .prepare_cached(SELECT_OLDEST_SEEN)
+                    .context(DbPrepareSnafu)?;
+                let oldest: Option<i64> = stmt
+                    .query_row([fan_id], |row| row.get(0))
+                    .context(DbReadSnafu)?;
+                Ok(oldest.is_none_or(|oldest| oldest >= now - FULL_WALK_AGE))
+            }
+            // buying a wish removes it, often while another one is added
+            CollectionKind::Wishlist => Ok(false),
+        }
+    }
+}
+
+// Refresh of a collection or a wishlist, page by page
+#[cfg(feature = "workers")]
+struct CollectionSync {
+    kind: CollectionKind,
+    fan_id: i64,
+    started: i64,
+    item_count: i64,
+    seen: HashSet<i64>,
+    // set while another page needs to be fetched
+    last_token: Option<String>,
+}
+
+#[cfg(feature = "workers")]
+impl CollectionSync {
+    fn new(kind: CollectionKind, fan_id: i64, started: i64, item_count: i64) -> Self {
+        CollectionSync {
+            kind,
+            fan_id,
+            started,
+            item_count,
+            seen: HashSet::new(),
+            last_token: None,
+        }
+    }
+
+    fn add_page(
+        &mut self,
+        db: &Connection,
+        items: &[Item],
+        more_available: bool,
+        next_token: Option<String>,
+    ) -> Result<(), Error> {
+        let mut reached_known = false;
+        for item in items {
+            let (item_id, known) = self.kind.add_item(db, self.fan_id, item, self.started)?;
+            reached_known |= known;
+            self.seen.insert(item_id);
+        }
+        self.last_token = None;
+        if !more_available {
+            self.remove_unseen(db)?;
+        } else if reached_known
+            && self.known_count(db)? == self.item_count
+            && self.kind.recently_walked(db, self.fan_id, self.started)?
+        {
+            // new items come first, so if the sizes match there is nothing left to add or remove
+        } else if next_token.is_none() {
+            // removals can't be told apart from the pages that weren't read
+            println!(
+                "No token for the next {} page of {}",
+                self.kind.name(),
+                self.fan_id
+            );
+        } else {
+            self.last_token = next_token;
+        }
+        Ok(())
+    }
+
+    fn known_count(&self, db: &Connection) -> Result<i64, Error> {
+        Ok(self.kind.get_item_ids(db, self.fan_id)?.len() as i64)
+    }
+
+    // Only valid once every page was seen
+    fn remove_unseen(&self, db: &Connection) -> Result<(), Error> {
+        for item_id in self.kind.get_item_ids(db, self.fan_id)? {
+            if !self.seen.contains(&item_id) {
+                self.kind
+                    .remove_item(db, self.fan_id, item_id, self.started)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "workers")]
 const SELECT_PRESENT_AND_RECENT_COLLECTOR: &str = r#"
-select unixepoch('now') - unixepoch(last_updated, '30 days') from collector where username = ?
+select collector_is_stale(last_updated, new_items) from collector
+left join collector_activity using (fan_id)
+where username = ?
 "#;
 
+#[cfg(feature = "workers")]
 fn collector_present_and_recent(db: &Connection, name: &str) -> Result<bool, Error> {
     let mut stmt = db
         .prepare_cached(SELECT_PRESENT_AND_RECENT_COLLECTOR)
@@ -55,17 +247,19 @@ fn collector_present_and_recent(db: &Connection, name: &str) -> Result<bool, Err
         .context(DbReadSnafu)?
         .next()
         .context(DbReadSnafu)?
-        .and_then(|row| row.get::<usize, i64>(0).ok())
-        .map(|v| v < 0)
+        .and_then(|row| row.get::<usize, bool>(0).ok())
+        .map(|stale| !stale)
         .unwrap_or(false); // not present
     Ok(present)
 }
 
+#[cfg(feature = "workers")]
 const INSERT_COLLECTOR: &str = r#"
 insert into collector (fan_id, username, name, token, last_updated)
 values (?, ?, ?, ?, 0)
 on conflict do update set token = case when token is null then excluded.token else token end"#;
 
+#[cfg(feature = "workers")]
 pub fn add_collector(db: &Connection, collector: &Collector) -> Result<(), Error> {
     let mut stmt = db
         .prepare_cached(INSERT_COLLECTOR)
@@ -80,6 +274,7 @@ pub fn add_collector(db: &Connection, collector: &Collector) -> Result<(), Error
     Ok(())
 }
 
+#[cfg(feature = "workers")]
 const INSERT_ITEM: &str = r#"
 insert into item (
     item_id, item_type, item_title, item_url, band_id, band_name, token,
@@ -87,12 +282,8 @@ insert into item (
 ) values (?, ?, ?, ?, ?, ?, ?, ?, 0)
 on conflict do update set token = case when token is null then excluded.token else token end"#;
 
-const INSERT_COLLECTS: &str = r#"
-insert or ignore into collects (fan_id, item_id)
-values (?, ?)
-returning 1"#;
-
-fn add_item_for_collector(db: &Connection, fan_id: i64, item: &Item) -> Result<bool, Error> {
+#[cfg(feature = "workers")]
+pub fn add_item(db: &Connection, item: &Item) -> Result<i64, Error> {
     let item_id = item.album_id.unwrap_or(item.item_id);
     let mut stmt = db.prepare_cached(INSERT_ITEM).context(DbPrepareSnafu)?;
     stmt.execute((
@@ -106,40 +297,149 @@ fn add_item_for_collector(db: &Connection, fan_id: i64, item: &Item) -> Result<b
         item.also_collected_count,
     ))
     .context(DbWriteSnafu)?;
+    Ok(item_id)
+}
+
+#[cfg(feature = "workers")]
+const INSERT_COLLECTS: &str = r#"
+insert or ignore into collects (fan_id, item_id)
+values (?, ?)
+returning 1"#;
+
+#[cfg(feature = "workers")]
+const UPSERT_HISTORY: &str = r#"
+insert into collection_history (fan_id, item_id, purchased, first_seen, last_seen)
+values (?1, ?2, ?3, ?4, ?4)
+on conflict do update
+set purchased = coalesce(excluded.purchased, purchased),
+    last_seen = excluded.last_seen,
+    removed = null"#;
+
+#[cfg(feature = "workers")]
+fn parse_purchased(value: &str) -> Option<i64> {
+    NaiveDateTime::parse_from_str(value, "%d %b %Y %H:%M:%S GMT")
+        .ok()
+        .map(|date| date.and_utc().timestamp())
+}
+
+#[cfg(feature = "workers")]
+fn add_item_for_collector(
+    db: &Connection,
+    fan_id: i64,
+    item: &Item,
+    now: i64,
+) -> Result<(i64, bool), Error> {
+    let item_id = add_item(db, item)?;
+    let purchased = item.purchased.as_deref().and_then(parse_purchased);
+    let mut stmt = db.prepare_cached(UPSERT_HISTORY).context(DbPrepareSnafu)?;
+    stmt.execute((fan_id, item_id, purchased, now))
+        .context(DbWriteSnafu)?;
     // query returns value if not present
     let mut stmt = db.prepare_cached(INSERT_COLLECTS).context(DbPrepareSnafu)?;
-    let res = stmt
+    let known = stmt
         .query((fan_id, item_id))
         .context(DbWriteSnafu)?
         .next()
         .context(DbReadSnafu)?
         .is_none();
-    Ok(res)
+    Ok((item_id, known))
 }
 
-struct InitialPage {
+#[cfg(feature = "workers")]
+const SELECT_COLLECTS: &str = r#"
+select item_id from collects where fan_id = ?"#;
+
+#[cfg(feature = "workers")]
+const DELETE_COLLECTS: &str = r#"
+delete from collects where fan_id = ? and item_id = ?"#;
+
+#[cfg(feature = "workers")]
+const UPDATE_HISTORY_REMOVED: &str = r#"
+update collection_history set removed = ?3
+where fan_id = ?1 and item_id = ?2 and removed is null"#;
+
+#[cfg(feature = "workers")]
+fn remove_item_for_collector(
+    db: &Connection,
     fan_id: i64,
-    last_token: Option<String>,
+    item_id: i64,
+    now: i64,
+) -> Result<(), Error> {
+    let mut stmt = db.prepare_cached(DELETE_COLLECTS).context(DbPrepareSnafu)?;
+    stmt.execute((fan_id, item_id)).context(DbWriteSnafu)?;
+    let mut stmt = db
+        .prepare_cached(UPDATE_HISTORY_REMOVED)
+        .context(DbPrepareSnafu)?;
+    stmt.execute((fan_id, item_id, now)).context(DbWriteSnafu)?;
+    Ok(())
+}
+
+// Pages are only read until the known items are reached, which misses a removal that happened
+// together with a purchase, so every item has to show up on a page once in a while
+#[cfg(feature = "workers")]
+const FULL_WALK_AGE: i64 = 90 * freshness::DAY;
+
+#[cfg(feature = "workers")]
+const SELECT_OLDEST_SEEN: &str = r#"
+select min(last_seen) from collection_history
+where fan_id = ? and removed is null"#;
+
+#[cfg(feature = "workers")]
+const INSERT_WISHES: &str = r#"
+insert or ignore into wishes (fan_id, item_id)
+values (?, ?)
+returning 1"#;
+
+#[cfg(feature = "workers")]
+fn add_item_for_wishlist(db: &Connection, fan_id: i64, item: &Item) -> Result<(i64, bool), Error> {
+    let item_id = add_item(db, item)?;
+    // query returns value if not present
+    let mut stmt = db.prepare_cached(INSERT_WISHES).context(DbPrepareSnafu)?;
+    let known = stmt
+        .query((fan_id, item_id))
+        .context(DbWriteSnafu)?
+        .next()
+        .context(DbReadSnafu)?
+        .is_none();
+    Ok((item_id, known))
+}
+
+#[cfg(feature = "workers")]
+const SELECT_WISHES: &str = r#"
+select item_id from wishes where fan_id = ?"#;
+
+#[cfg(feature = "workers")]
+const DELETE_WISH: &str = r#"
+delete from wishes where fan_id = ? and item_id = ?"#;
+
+// The initial page only has the first batch of items, in no particular order
+#[cfg(feature = "workers")]
+fn add_initial_items(
+    db: &Connection,
+    sync: &mut CollectionSync,
+    items: HashMap<String, Item>,
+    data: Option<&CollectionData>,
+) -> Result<(), Error> {
+    let items = items.into_values().collect::<Vec<_>>();
+    let more_available = data.is_some_and(|data| data.item_count > data.batch_size);
+    let next_token = data.and_then(|data| data.last_token.clone());
+    sync.add_page(db, &items, more_available, next_token)
+}
+
+#[cfg(feature = "workers")]
+struct InitialPage {
+    collection: CollectionSync,
+    wishlist: CollectionSync,
 }
 
+#[cfg(feature = "workers")]
 async fn get_initial_page(
     db: &Pool<SqliteConnectionManager>,
+    client: &BandcampClient,
     name: &str,
 ) -> Result<InitialPage, Error> {
     println!("Reading initial page for {name}");
-    let client = Client::new();
-    let page = client
-        .get(format!("https://bandcamp.com/{name}"))
-        .send()
-        .await
-        .context(NetworkSnafu)?;
-    if page.status() == StatusCode::TOO_MANY_REQUESTS {
-        return Err(Error::RateLimit);
-    }
-    if page.status() == StatusCode::NOT_FOUND {
-        return Err(Error::NotFoundError);
-    }
-    let body = page.text().await.context(NetworkSnafu)?;
+    let body = client.get(&client.url(&format!("/{name}"))).await?;
     let db = db.clone();
     spawn_blocking(move || {
         let soup = Soup::new(&body);
@@ -147,72 +447,96 @@ async fn get_initial_page(
         let attrs = node.attrs();
         let body = attrs.get("data-blob").context(PageSnafu)?;
         let result: InitialResult = serde_json::from_str(body).context(SerializationSnafu)?;
-        let conn = db.get().context(DbPoolSnafu)?;
-        add_collector(&conn, &result.fan_data)?;
-        let mut done = false;
-        for entry in result.item_cache.collection.into_values() {
-            done = add_item_for_collector(&conn, result.fan_data.fan_id, &entry)?;
-        }
-        let more_available =
-            !done && result.collection_data.item_count > result.collection_data.batch_size;
+        let mut conn = db.get().context(DbPoolSnafu)?;
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context(DbWriteSnafu)?;
+        let fan_id = result.fan_data.fan_id;
+        let started = freshness::now();
+        add_collector(&tx, &result.fan_data)?;
+        let mut collection = CollectionSync::new(
+            CollectionKind::Collection,
+            fan_id,
+            started,
+            result.collection_data.item_count,
+        );
+        add_initial_items(
+            &tx,
+            &mut collection,
+            result.item_cache.collection,
+            Some(&result.collection_data),
+        )?;
+        let mut wishlist = CollectionSync::new(
+            CollectionKind::Wishlist,
+            fan_id,
+            started,
+            result
+                .wishlist_data
+                .as_ref()
+                .map_or(0, |data| data.item_count),
+        );
+        add_initial_items(
+            &tx,
+            &mut wishlist,
+            result.item_cache.wishlist,
+            result.wishlist_data.as_ref(),
+        )?;
+        tx.commit().context(DbWriteSnafu)?;
         Ok(InitialPage {
-            fan_id: result.fan_data.fan_id,
-            last_token: if more_available {
-                result.collection_data.last_token
-            } else {
-                None
-            },
+            collection,
+            wishlist,
         })
     })
     .await
     .unwrap()
 }
 
+#[cfg(feature = "workers")]
 async fn get_next_page(
     db: &Pool<SqliteConnectionManager>,
-    fan_id: i64,
-    mut last_token: String,
-) -> Result<Option<String>, Error> {
-    let client = Client::new();
-    let result = client
-        .post("https://bandcamp.com/api/fancollection/1/collection_items")
-        .body(
+    client: &BandcampClient,
+    mut sync: CollectionSync,
+) -> Result<CollectionSync, Error> {
+    let body = client
+        .post(
+            sync.kind.path(),
             json!({
                 "count": 500,
-                "fan_id": fan_id,
-                "older_than_token": last_token,
-            })
-            .to_string(),
+                "fan_id": sync.fan_id,
+                "older_than_token": sync.last_token,
+            }),
         )
-        .send()
-        .await
-        .context(NetworkSnafu)?;
-    if result.status() == StatusCode::TOO_MANY_REQUESTS {
-        return Err(Error::RateLimit);
-    }
-    let body = result.text().await.context(NetworkSnafu)?;
+        .await?;
     let db = db.clone();
     spawn_blocking(move || {
         let collection_result: CollectionResult =
             serde_json::from_str(&body).context(SerializationSnafu)?;
-        let mut done = false;
-        let conn = db.get().context(DbPoolSnafu)?;
-        for entry in collection_result.items {
-            done = add_item_for_collector(&conn, fan_id, &entry)?;
-            last_token = entry.token.unwrap();
-        }
-        if done || !collection_result.more_available {
-            Ok(None)
-        } else {
-            Ok(Some(last_token))
-        }
+        let next_token = collection_result
+            .items
+            .last()
+            .and_then(|item| item.token.clone());
+        let mut conn = db.get().context(DbPoolSnafu)?;
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context(DbWriteSnafu)?;
+        sync.add_page(
+            &tx,
+            &collection_result.items,
+            collection_result.more_available,
+            next_token,
+        )?;
+        tx.commit().context(DbWriteSnafu)?;
+        Ok(sync)
     })
     .await
     .unwrap()
 }
 
+// Fetches new items until the known ones are reached, and everything if items might be missing
+#[cfg(feature = "workers")]
 pub async fn fetch_collection(
     db: &Pool<SqliteConnectionManager>,
+    client: &BandcampClient,
     name: &str,
     force: bool,
 ) -> Result<(), Error> {
@@ -221,22 +545,23 @@ pub async fn fetch_collection(
         return Ok(());
     }
     drop(conn);
-    let result = get_initial_page(db, name).await?;
-    if let Some(mut last_token) = result.last_token {
-        println!("Reading next page for {name}");
-        while let Some(token) = get_next_page(db, result.fan_id, last_token).await? {
-            println!("Reading next page for {name}");
-            last_token = token
+    let result = get_initial_page(db, client, name).await?;
+    for mut sync in [result.collection, result.wishlist] {
+        while sync.last_token.is_some() {
+            println!("Reading next {} page for {name}", sync.kind.name());
+            sync = get_next_page(db, client, sync).await?;
         }
     }
     Ok(())
 }
 
+#[cfg(feature = "server")]
 const SELECT_COLLECTION_SIZE: &str = r#"
 select count(*) from collector
 join collects using (fan_id)
 where username = ?"#;
 
+#[cfg(feature = "server")]
 pub fn get_collection_size(db: &Pool<SqliteConnectionManager>, name: &str) -> Result<u64, Error> {
     let conn = db.get().context(DbPoolSnafu)?;
     let mut stmt = conn
@@ -251,116 +576,130 @@ pub fn get_collection_size(db: &Pool<SqliteConnectionManager>, name: &str) -> Re
     Ok(result.unwrap_or(0))
 }
 
-const SELECT_FIRST_QUEUE_COLLECTOR: &str = r#"
-select username from collector_collection_queue
-join collector using (fan_id)
-order by fan_id asc
-limit 1"#;
+const SELECT_PURCHASES: &str = r#"
+select item.*, coalesce(purchased, first_seen) as purchased_at, first_seen, last_seen, removed
+from collection_history
+join item using (item_id)
+where fan_id = ?
+order by purchased_at desc, item_id desc
+limit ?"#;
 
+// Most recent first, including items that were removed from the collection since
+pub fn get_purchases(db: &Connection, fan_id: i64, limit: usize) -> Result<Vec<Purchase>, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_PURCHASES)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query((fan_id, limit))
+        .context(DbReadSnafu)?
+        .map(purchase_from_row)
+        .collect()
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+// collectors with a job are either queued already or waiting for a retry
+#[cfg(feature = "workers")]
 const SELECT_UNFINISHED: &str = r#"
-select username from collector
-where unixepoch('now') > unixepoch(last_updated, '30 days')
+select fan_id from collector
+left join collector_activity using (fan_id)
+where collector_is_stale(last_updated, new_items)
+and fan_id not in (select target_id from job where kind = 'collector')
 order by fan_id asc
 limit 1"#;
 
-fn get_next_collector(db: &Connection, crawl: bool) -> Result<Option<String>, Error> {
-    let mut stmt = db
-        .prepare_cached(SELECT_FIRST_QUEUE_COLLECTOR)
-        .context(DbPrepareSnafu)?;
-    let mut rows = stmt.query([]).context(DbReadSnafu)?;
-    let row = rows.next().context(DbReadSnafu)?;
-    if let Some(row) = row {
-        let username: String = row.get("username").context(DbReadSnafu)?;
-        Ok(Some(username))
-    } else if crawl {
-        let mut stmt = db
-            .prepare_cached(SELECT_UNFINISHED)
-            .context(DbPrepareSnafu)?;
-        let mut rows = stmt.query([]).context(DbReadSnafu)?;
-        let row = rows.next().context(DbReadSnafu)?;
-        if let Some(row) = row {
-            let username: String = row.get("username").context(DbReadSnafu)?;
-            Ok(Some(username))
-        } else {
+#[cfg(feature = "workers")]
+fn get_next_collector(db: &Connection, crawl: bool) -> Result<Option<(i64, String)>, Error> {
+    let fan_id = match jobs::next_job(db, JobKind::Collector)? {
+        Some(fan_id) => fan_id,
+        None if crawl && !jobs::crawl_paused(db)? => {
+            let mut stmt = db
+                .prepare_cached(SELECT_UNFINISHED)
+                .context(DbPrepareSnafu)?;
+            let fan_id = stmt
+                .query_row([], |row| row.get(0))
+                .optional()
+                .context(DbReadSnafu)?;
+            let Some(fan_id) = fan_id else {
+                return Ok(None);
+            };
+            fan_id
+        }
+        None => return Ok(None),
+    };
+    match get_username_for_fan_id(db, fan_id)? {
+        Some(username) => Ok(Some((fan_id, username))),
+        None => {
+            // the collector was removed since the job was queued
+            jobs::complete(db, JobKind::Collector, fan_id)?;
             Ok(None)
         }
-    } else {
-        Ok(None)
     }
 }
 
+#[cfg(feature = "workers")]
 const MARK_COLLECTOR_DONE: &str = r#"
 update collector
 set last_updated = unixepoch('now')
 where username = ?"#;
 
+#[cfg(feature = "workers")]
 fn mark_collector_done(db: &Connection, name: &str) -> Result<(), Error> {
     let mut stmt = db
         .prepare_cached(MARK_COLLECTOR_DONE)
         .context(DbPrepareSnafu)?;
     stmt.execute([name]).context(DbWriteSnafu)?;
-    Ok(())
+    freshness::update_collector_activity(db, name)
 }
 
-const DELETE_QUEUE_COLLECTOR: &str = r#"
-delete from collector_collection_queue where fan_id = (
-select fan_id from collector where username = ?
-)"#;
-
-fn remove_from_queue(db: &Connection, collector: &str) -> Result<(), Error> {
-    let mut stmt = db
-        .prepare_cached(DELETE_QUEUE_COLLECTOR)
-        .context(DbPrepareSnafu)?;
-    stmt.execute([collector]).context(DbWriteSnafu)?;
-    Ok(())
-}
-
-const DELETE_COLLECTS: &str = r#"
-delete from collects where fan_id = (
-select fan_id from collector where username = ?
-)"#;
-
-fn remove_collects(db: &Connection, name: &str) -> Result<(), Error> {
-    let mut stmt = db.prepare_cached(DELETE_COLLECTS).context(DbPrepareSnafu)?;
-    stmt.execute([name]).context(DbWriteSnafu)?;
-    Ok(())
-}
+// Polling interval while there is nothing to fetch
+#[cfg(feature = "workers")]
+const IDLE_INTERVAL: Duration = Duration::from_secs(3);
 
+#[cfg(feature = "workers")]
 pub async fn collection_worker(
     db: &Pool<SqliteConnectionManager>,
+    client: &BandcampClient,
     crawl: bool,
     run_state: &AtomicBool,
 ) -> Result<(), Error> {
-    let mut timer = interval(Duration::from_secs(3));
-    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
     while run_state.load(Ordering::Relaxed) {
         let conn = db.get().context(DbPoolSnafu)?;
-        if let Some(collector) = get_next_collector(&conn, crawl)? {
+        if let Some((fan_id, collector)) = get_next_collector(&conn, crawl)? {
+            if collector_present_and_recent(&conn, &collector)? {
+                // refreshed since it was queued, this would skew the measured durations
+                jobs::complete(&conn, JobKind::Collector, fan_id)?;
+                continue;
+            }
             drop(conn);
-            match fetch_collection(db, &collector, false).await {
+            let started = Instant::now();
+            match fetch_collection(db, client, &collector, false).await {
                 Err(Error::RateLimit) => {
-                    println!("Rate limited, waiting 10 seconds");
-                    let conn = db.get().context(DbPoolSnafu)?;
-                    remove_collects(&conn, &collector)?;
-                    sleep(Duration::from_secs(10)).await
+                    // the shared limiter is already backing off, retry later
+                    println!("Rate limited, retrying collector {collector} later");
                 }
                 Err(Error::NotFoundError) => {
                     println!("Collector {collector} not found");
                     let conn = db.get().context(DbPoolSnafu)?;
                     mark_collector_done(&conn, &collector)?;
-                    remove_from_queue(&conn, &collector)?;
+                    jobs::complete(&conn, JobKind::Collector, fan_id)?;
+                    jobs::record_duration(&conn, JobKind::Collector, started.elapsed())?;
                 }
                 Err(err) => {
                     println!("Error while processing collector {collector}: {err}");
+                    let conn = db.get().context(DbPoolSnafu)?;
+                    jobs::record_failure(&conn, JobKind::Collector, fan_id, &err)?;
                 }
                 Ok(()) => {
                     let conn = db.get().context(DbPoolSnafu)?;
                     mark_collector_done(&conn, &collector)?;
-                    remove_from_queue(&conn, &collector)?;
+                    jobs::complete(&conn, JobKind::Collector, fan_id)?;
+                    jobs::record_duration(&conn, JobKind::Collector, started.elapsed())?;
                 }
             }
+        } else {
+            sleep(IDLE_INTERVAL).await;
         }
-        timer.tick().await;
     }
     Ok(())
 }
@@ -384,3 +723,156 @@ pub fn get_fan_id_for_username(db: &Connection, name: &str) -> Result<Option<i64
         .context(DbReadSnafu);
     result
 }
+
+const SELECT_NAME_FOR_FAN_ID: &str = r#"
+select username from collector where fan_id = ?
+"#;
+
+pub fn get_username_for_fan_id(db: &Connection, fan_id: i64) -> Result<Option<String>, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_NAME_FOR_FAN_ID)
+        .context(DbPrepareSnafu)?;
+    #[allow(clippy::let_and_return)]
+    let result = stmt
+        .query([fan_id])
+        .context(DbReadSnafu)?
+        .next()
+        .context(DbReadSnafu)?
+        .map(|r| r.get(0))
+        .transpose()
+        .context(DbReadSnafu);
+    result
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::mock_server::MockServer;
+    use crate::store::test_pool;
+
+    #[actix_web::test]
+    async fn fetches_all_collection_and_wishlist_pages() {
+        let server = MockServer::start();
+        let db = test_pool("fetch_collection");
+        fetch_collection(&db, &server.client(), "testfan", true)
+            .await
+            .unwrap();
+        assert_eq!(get_collection_size(&db, "testfan").unwrap(), 3);
+        let conn = db.get().unwrap();
+        assert_eq!(get_fan_id_for_username(&conn, "testfan").unwrap(), Some(1));
+        let wishes: i64 = conn
+            .query_row("select count(*) from wishes where fan_id = 1", [], |r| {
+                r.get(0)
+            })
+            .unwrap();
+        assert_eq!(wishes, 1);
+    }
+
+    #[actix_web::test]
+    async fn tracks_purchases_and_removals() {
+        let server = MockServer::start();
+        let db = test_pool("collection_history");
+        let conn = db.get().unwrap();
+        conn.execute_batch(
+            "insert into collector values (1, 'testfan', 'Test Fan', null, 0);
+            insert into item values (999, 'album', 'Gone', '', 9, 'Band', null, 0, 0);
+            insert into collects values (1, 999);
+            insert into collection_history values (1, 999, null, 10, 10, null);",
+        )
+        .unwrap();
+        fetch_collection(&db, &server.client(), "testfan", true)
+            .await
+            .unwrap();
+        assert_eq!(get_collection_size(&db, "testfan").unwrap(), 3);
+        let purchases = get_purchases(&conn, 1, 10).unwrap();
+        let ids = purchases.iter().map(|p| p.item.item_id).collect::<Vec<_>>();
+        // 101 has a purchase date from 2023, the others were first seen just now
+        assert_eq!(ids, vec![103, 102, 101, 999]);
+        assert_eq!(purchases[2].purchased_at, 1689798427);
+        assert!(purchases[0].removed.is_none());
+        assert!(purchases[3].removed.is_some());
+    }
+
+    fn item(item_id: i64) -> Item {
+        serde_json::from_value(json!({
+            "item_id": item_id,
+            "item_type": "album",
+            "item_title": format!("Item {item_id}"),
+            "item_url": "",
+            "band_id": item_id,
+            "band_name": "Band",
+            "token": format!("token{item_id}"),
+            "also_collected_count": 0,
+        }))
+        .unwrap()
+    }
+
+    fn collects(conn: &Connection) -> Vec<i64> {
+        let mut ids = CollectionKind::Collection.get_item_ids(conn, 1).unwrap();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn only_stops_early_after_a_recent_full_walk() {
+        let db = test_pool("collection_sync");
+        let conn = db.get().unwrap();
+        let now = freshness::now();
+        conn.execute_batch(
+            "insert into collector values (1, 'a', 'A', null, 0);
+            insert into item values (1, 'album', '', '', 1, '', null, 0, 0),
+                (2, 'album', '', '', 2, '', null, 0, 0);
+            insert into collects values (1, 1), (1, 2);
+            insert into collection_history values (1, 1, null, 0, 0, null), (1, 2, null, 0, 0, null);",
+        )
+        .unwrap();
+        // the counts match, but the items weren't seen in a long time
+        let mut sync = CollectionSync::new(CollectionKind::Collection, 1, now, 2);
+        sync.add_page(&conn, &[item(1)], true, Some("token1".to_string()))
+            .unwrap();
+        assert_eq!(sync.last_token.as_deref(), Some("token1"));
+        sync.add_page(&conn, &[], false, None).unwrap();
+        assert_eq!(collects(&conn), vec![1]);
+
+        // after the full walk a matching count is trusted
+        conn.execute_batch("insert into collects values (1, 2)")
+            .unwrap();
+        let mut sync = CollectionSync::new(CollectionKind::Collection, 1, now, 2);
+        sync.add_page(&conn, &[item(1)], true, Some("token1".to_string()))
+            .unwrap();
+        assert_eq!(sync.last_token, None);
+        assert_eq!(collects(&conn), vec![1, 2]);
+    }
+
+    #[test]
+    fn keeps_items_when_the_next_page_is_unreachable() {
+        let db = test_pool("collection_sync_no_token");
+        let conn = db.get().unwrap();
+        conn.execute_batch(
+            "insert into collector values (1, 'a', 'A', null, 0);
+            insert into item values (1, 'album', '', '', 1, '', null, 0, 0);
+            insert into collects values (1, 1);",
+        )
+        .unwrap();
+        let mut sync = CollectionSync::new(CollectionKind::Collection, 1, freshness::now(), 3);
+        sync.add_page(&conn, &[item(2)], true, None).unwrap();
+        assert_eq!(sync.last_token, None);
+        assert_eq!(collects(&conn), vec![1, 2]);
+    }
+
+    #[actix_web::test]
+    async fn reports_missing_user() {
+        let server = MockServer::start();
+        let db = test_pool("missing_user");
+        let result = fetch_collection(&db, &server.client(), "nobody", true).await;
+        assert!(matches!(result, Err(Error::NotFoundError)));
+    }
+
+    #[actix_web::test]
+    async fn reports_rate_limit() {
+        let server = MockServer::start();
+        let db = test_pool("rate_limit");
+        let result = fetch_collection(&db, &server.client(), "ratelimited", true).await;
+        assert!(matches!(result, Err(Error::RateLimit)));
+    }
+}
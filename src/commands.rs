@@ -0,0 +1,120 @@
+use crate::args::{ExportArgs, ImportArgs, MigrateArgs, OutputFormat, RecommendArgs, StatsArgs};
+use bandcamp_recommendations::{
+    export_table, get_stats, get_user_recommendations, import_table, Error, ExportOptions,
+    RankingCache, RankingParams, RecommendationFilter, Store, LATEST_VERSION,
+};
+use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
+
+fn truncate(value: &str, width: usize) -> String {
+    if value.chars().count() > width {
+        let mut result = value.chars().take(width - 1).collect::<String>();
+        result.push('…');
+        result
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn recommend(store: &Store, args: &RecommendArgs) -> Result<(), Error> {
+    let params = RankingParams::new(
+        args.strategy,
+        args.similar_boost,
+        args.popularity_penalty,
+        false,
+    );
+    let filter = RecommendationFilter {
+        exclude_known_bands: args.exclude_known_bands,
+        ..Default::default()
+    };
+    let page = get_user_recommendations(
+        store,
+        &RankingCache::default(),
+        &args.username,
+        &params,
+        &filter,
+        args.offset,
+        args.limit,
+    )?;
+    if args.format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&page).unwrap());
+        return Ok(());
+    }
+    println!(
+        "{:>4}  {:>8}  {:<32}  {:<24}  url",
+        "#", "score", "title", "band"
+    );
+    for (index, item) in page.items.iter().enumerate() {
+        println!(
+            "{:>4}  {:>8.2}  {:<32}  {:<24}  {}",
+            page.offset + index + 1,
+            item.score.unwrap_or_default(),
+            truncate(&item.item_title, 32),
+            truncate(&item.band_name, 24),
+            item.item_url
+        );
+    }
+    println!("{} of {} candidates", page.items.len(), page.total);
+    Ok(())
+}
+
+pub fn stats(store: &Store, args: &StatsArgs) -> Result<(), Error> {
+    let conn = store.connection()?;
+    let stats = get_stats(&conn)?;
+    if args.format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&stats).unwrap());
+        return Ok(());
+    }
+    for (name, value) in stats.rows() {
+        println!("{name:<20} {value:>12}");
+    }
+    Ok(())
+}
+
+pub fn export(store: &Store, args: &ExportArgs) -> Result<(), Error> {
+    let conn = store.connection()?;
+    let out: Box<dyn Write + Send> = match &args.output {
+        Some(path) => Box::new(File::create(path).map_err(|source| Error::IoError { source })?),
+        None => Box::new(stdout()),
+    };
+    let options = ExportOptions {
+        format: args.format,
+        anonymize: args.anonymize,
+        salt: args.salt.clone().unwrap_or_default(),
+    };
+    let count = export_table(&conn, args.table, &options, BufWriter::new(out))?;
+    // stdout might be the export itself
+    eprintln!("Exported {count} rows");
+    Ok(())
+}
+
+pub fn import(store: &Store, args: &ImportArgs) -> Result<(), Error> {
+    let mut conn = store.connection()?;
+    let count = import_table(&mut conn, args.table, args.format, &args.input)?;
+    println!("Imported {count} rows");
+    Ok(())
+}
+
+pub fn migrate(store: &Store, args: &MigrateArgs) -> Result<(), Error> {
+    println!(
+        "Schema version {}, latest {LATEST_VERSION}",
+        store.schema_version()?
+    );
+    let migrations = store.migrate(args.dry_run)?;
+    if migrations.is_empty() {
+        println!("Schema is up to date");
+    } else {
+        let verb = if args.dry_run {
+            "Would apply"
+        } else {
+            "Applied"
+        };
+        for migration in migrations {
+            println!(
+                "{verb} migration {} ({})",
+                migration.version, migration.name
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,340 @@
+#[cfg(feature = "workers")]
+use crate::{DbPoolSnafu, DbWriteSnafu};
+use crate::{DbPrepareSnafu, DbReadSnafu, Error};
+use fallible_iterator::FallibleIterator;
+#[cfg(feature = "workers")]
+use r2d2::Pool;
+#[cfg(feature = "workers")]
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+#[cfg(feature = "workers")]
+use rusqlite::TransactionBehavior;
+use snafu::ResultExt;
+use std::collections::HashSet;
+#[cfg(feature = "workers")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "workers")]
+use std::time::Duration;
+#[cfg(feature = "workers")]
+use tokio::task::spawn_blocking;
+#[cfg(feature = "workers")]
+use tokio::time::{interval, MissedTickBehavior};
+
+// Pairs grow quadratically, and whale collections say little about any single item anyway
+#[cfg(feature = "workers")]
+const MAX_COLLECTION_SIZE: usize = 1000;
+
+#[cfg(feature = "workers")]
+const SELECT_FIRST_QUEUE_FAN: &str = r#"
+select fan_id from cooccurrence_queue
+order by fan_id asc
+limit 1"#;
+
+#[cfg(feature = "workers")]
+fn get_next_fan(db: &Connection) -> Result<Option<i64>, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_FIRST_QUEUE_FAN)
+        .context(DbPrepareSnafu)?;
+    let mut rows = stmt.query([]).context(DbReadSnafu)?;
+    let row = rows.next().context(DbReadSnafu)?;
+    row.map(|row| row.get(0)).transpose().context(DbReadSnafu)
+}
+
+fn get_items(db: &Connection, query: &str, fan_id: i64) -> Result<HashSet<i64>, Error> {
+    let mut stmt = db.prepare_cached(query).context(DbPrepareSnafu)?;
+    let result = stmt
+        .query([fan_id])
+        .context(DbReadSnafu)?
+        .map(|row| row.get(0))
+        .collect()
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+#[cfg(feature = "workers")]
+const SELECT_COLLECTS: &str = r#"
+select item_id from collects where fan_id = ?"#;
+
+const SELECT_COUNTED: &str = r#"
+select item_id from cooccurrence_collects where fan_id = ?"#;
+
+#[cfg(feature = "workers")]
+const UPDATE_PAIR: &str = r#"
+insert into item_cooccurrence (item_id, other_id, count)
+values (?1, ?2, ?3), (?2, ?1, ?3)
+on conflict do update set count = count + excluded.count"#;
+
+#[cfg(feature = "workers")]
+const UPDATE_OCCURRENCE: &str = r#"
+insert into item_occurrence (item_id, count)
+values (?, ?)
+on conflict do update set count = count + excluded.count"#;
+
+#[cfg(feature = "workers")]
+const INSERT_COUNTED: &str = r#"
+insert into cooccurrence_collects (fan_id, item_id) values (?, ?)"#;
+
+#[cfg(feature = "workers")]
+const DELETE_COUNTED: &str = r#"
+delete from cooccurrence_collects where fan_id = ? and item_id = ?"#;
+
+#[cfg(feature = "workers")]
+const DELETE_EMPTY_PAIRS: &str = r#"
+delete from item_cooccurrence where item_id = ? and count <= 0"#;
+
+#[cfg(feature = "workers")]
+const DELETE_EMPTY_OCCURRENCE: &str = r#"
+delete from item_occurrence where item_id = ? and count <= 0"#;
+
+#[cfg(feature = "workers")]
+fn update_item(
+    db: &Connection,
+    fan_id: i64,
+    item_id: i64,
+    counted: &HashSet<i64>,
+    delta: i64,
+) -> Result<(), Error> {
+    let mut stmt = db.prepare_cached(UPDATE_PAIR).context(DbPrepareSnafu)?;
+    for other_id in counted {
+        stmt.execute((item_id, other_id, delta))
+            .context(DbWriteSnafu)?;
+    }
+    let mut stmt = db
+        .prepare_cached(UPDATE_OCCURRENCE)
+        .context(DbPrepareSnafu)?;
+    stmt.execute((item_id, delta)).context(DbWriteSnafu)?;
+    if delta > 0 {
+        let mut stmt = db.prepare_cached(INSERT_COUNTED).context(DbPrepareSnafu)?;
+        stmt.execute((fan_id, item_id)).context(DbWriteSnafu)?;
+    } else {
+        let mut stmt = db.prepare_cached(DELETE_COUNTED).context(DbPrepareSnafu)?;
+        stmt.execute((fan_id, item_id)).context(DbWriteSnafu)?;
+        let mut stmt = db
+            .prepare_cached(DELETE_EMPTY_PAIRS)
+            .context(DbPrepareSnafu)?;
+        for id in counted.iter().chain([&item_id]) {
+            stmt.execute([id]).context(DbWriteSnafu)?;
+        }
+        let mut stmt = db
+            .prepare_cached(DELETE_EMPTY_OCCURRENCE)
+            .context(DbPrepareSnafu)?;
+        stmt.execute([item_id]).context(DbWriteSnafu)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "workers")]
+const DELETE_QUEUE_FAN: &str = r#"
+delete from cooccurrence_queue where fan_id = ?"#;
+
+// Pair updates per transaction, so large collections don't block other writers for long
+#[cfg(feature = "workers")]
+const PAIRS_PER_TRANSACTION: usize = 10_000;
+
+// Moves the counted items of a fan towards collects, one item at a time so every pair is
+// counted exactly once. Returns whether the fan is in sync and left the queue
+#[cfg(feature = "workers")]
+fn sync_chunk(db: &Connection, fan_id: i64) -> Result<bool, Error> {
+    let mut target = get_items(db, SELECT_COLLECTS, fan_id)?;
+    if target.len() > MAX_COLLECTION_SIZE {
+        target.clear();
+    }
+    let mut counted = get_items(db, SELECT_COUNTED, fan_id)?;
+    let mut pairs = 0;
+    let removed = counted.difference(&target).copied().collect::<Vec<_>>();
+    for item_id in removed {
+        if pairs >= PAIRS_PER_TRANSACTION {
+            return Ok(false);
+        }
+        counted.remove(&item_id);
+        update_item(db, fan_id, item_id, &counted, -1)?;
+        pairs += counted.len() + 1;
+    }
+    let added = target.difference(&counted).copied().collect::<Vec<_>>();
+    for item_id in added {
+        if pairs >= PAIRS_PER_TRANSACTION {
+            return Ok(false);
+        }
+        update_item(db, fan_id, item_id, &counted, 1)?;
+        counted.insert(item_id);
+        pairs += counted.len();
+    }
+    let mut stmt = db
+        .prepare_cached(DELETE_QUEUE_FAN)
+        .context(DbPrepareSnafu)?;
+    stmt.execute([fan_id]).context(DbWriteSnafu)?;
+    Ok(true)
+}
+
+// Every chunk rereads both sides, so changes to collects in between and other workers
+// syncing the same fan are picked up
+#[cfg(feature = "workers")]
+pub(crate) fn sync_fan(db: &mut Connection, fan_id: i64) -> Result<(), Error> {
+    loop {
+        let tx = db
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context(DbWriteSnafu)?;
+        let done = sync_chunk(&tx, fan_id)?;
+        tx.commit().context(DbWriteSnafu)?;
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+const SELECT_COOCCURRENCES: &str = r#"
+select other_id, count from item_cooccurrence
+where item_id = ?
+order by count desc
+limit ?"#;
+
+pub fn get_cooccurrences(
+    db: &Connection,
+    item_id: i64,
+    limit: usize,
+) -> Result<Vec<(i64, i64)>, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_COOCCURRENCES)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query((item_id, limit))
+        .context(DbReadSnafu)?
+        .map(|row| Ok((row.get(0)?, row.get(1)?)))
+        .collect()
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+const SELECT_OCCURRENCE: &str = r#"
+select count from item_occurrence where item_id = ?"#;
+
+pub fn get_occurrence(db: &Connection, item_id: i64) -> Result<i64, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_OCCURRENCE)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query([item_id])
+        .context(DbReadSnafu)?
+        .next()
+        .context(DbReadSnafu)?
+        .map(|row| row.get(0))
+        .transpose()
+        .context(DbReadSnafu)?;
+    Ok(result.unwrap_or(0))
+}
+
+pub fn get_counted_items(db: &Connection, fan_id: i64) -> Result<HashSet<i64>, Error> {
+    get_items(db, SELECT_COUNTED, fan_id)
+}
+
+#[cfg(feature = "workers")]
+pub async fn cooccurrence_worker(
+    db: &Pool<SqliteConnectionManager>,
+    run_state: &AtomicBool,
+) -> Result<(), Error> {
+    let mut timer = interval(Duration::from_secs(1));
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    while run_state.load(Ordering::Relaxed) {
+        let db = db.clone();
+        // drain the queue, but check for shutdown in between fans
+        let worked = spawn_blocking(move || {
+            let mut conn = db.get().context(DbPoolSnafu)?;
+            match get_next_fan(&conn)? {
+                Some(fan_id) => sync_fan(&mut conn, fan_id).map(|_| true),
+                None => Ok(false),
+            }
+        })
+        .await
+        .unwrap()?;
+        if !worked {
+            timer.tick().await;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "workers"))]
+mod tests {
+    use super::*;
+    use crate::store::test_pool;
+
+    fn pair_count(db: &Connection, item_id: i64, other_id: i64) -> Option<i64> {
+        get_cooccurrences(db, item_id, 2000)
+            .unwrap()
+            .into_iter()
+            .find(|(id, _)| *id == other_id)
+            .map(|(_, count)| count)
+    }
+
+    fn queued(db: &Connection) -> Option<i64> {
+        get_next_fan(db).unwrap()
+    }
+
+    #[test]
+    fn counts_added_and_removed_items() {
+        let store = test_pool("cooccurrence");
+        let mut conn = store.connection().unwrap();
+        conn.execute_batch(
+            "with recursive n(x) as (select 1 union all select x + 1 from n where x < 1001)
+            insert into item select x, 'album', '', '', x, '', null, 0, 0 from n;
+            insert into collector values (1, 'a', '', null, 0), (2, 'b', '', null, 0);
+            insert into collects values (1, 1), (1, 2), (1, 3), (2, 1), (2, 2);",
+        )
+        .unwrap();
+        assert_eq!(queued(&conn), Some(1));
+        sync_fan(&mut conn, 1).unwrap();
+        sync_fan(&mut conn, 2).unwrap();
+        assert_eq!(queued(&conn), None);
+        assert_eq!(pair_count(&conn, 1, 2), Some(2));
+        assert_eq!(pair_count(&conn, 2, 1), Some(2));
+        assert_eq!(pair_count(&conn, 3, 1), Some(1));
+        assert_eq!(get_occurrence(&conn, 1).unwrap(), 2);
+        assert_eq!(
+            get_counted_items(&conn, 1).unwrap(),
+            HashSet::from([1, 2, 3])
+        );
+
+        // the trigger queues the fan again, empty pairs are deleted
+        conn.execute("delete from collects where fan_id = 1 and item_id = 3", [])
+            .unwrap();
+        assert_eq!(queued(&conn), Some(1));
+        sync_fan(&mut conn, 1).unwrap();
+        assert_eq!(pair_count(&conn, 1, 3), None);
+        assert_eq!(pair_count(&conn, 3, 2), None);
+        assert_eq!(pair_count(&conn, 1, 2), Some(2));
+        assert_eq!(get_occurrence(&conn, 3).unwrap(), 0);
+
+        // whale collections are not counted at all, and take their old pairs with them
+        conn.execute_batch("insert into collects select 1, item_id from item where item_id > 2")
+            .unwrap();
+        sync_fan(&mut conn, 1).unwrap();
+        assert!(get_counted_items(&conn, 1).unwrap().is_empty());
+        assert_eq!(pair_count(&conn, 1, 2), Some(1));
+        assert_eq!(get_occurrence(&conn, 500).unwrap(), 0);
+        assert_eq!(queued(&conn), None);
+    }
+
+    #[test]
+    fn syncs_large_collections_in_chunks() {
+        let store = test_pool("cooccurrence_chunks");
+        let mut conn = store.connection().unwrap();
+        conn.execute_batch(
+            "with recursive n(x) as (select 1 union all select x + 1 from n where x < 200)
+            insert into item select x, 'album', '', '', x, '', null, 0, 0 from n;
+            insert into collector values (1, 'a', '', null, 0);
+            insert into collects select 1, item_id from item;",
+        )
+        .unwrap();
+        // 19900 pairs don't fit in one transaction
+        assert!(!sync_chunk(&conn, 1).unwrap());
+        assert_eq!(queued(&conn), Some(1));
+        sync_fan(&mut conn, 1).unwrap();
+        let pairs: i64 = conn
+            .query_row("select sum(count) from item_cooccurrence", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(pairs, 200 * 199);
+        assert_eq!(queued(&conn), None);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::items;
+use crate::{DbPrepareSnafu, DbReadSnafu, DbWriteSnafu, Error, NotFoundSnafu};
+use fallible_iterator::FallibleIterator;
+use rusqlite::Connection;
+use snafu::{ensure, ResultExt};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Dismissal {
+    Item(i64),
+    Band(i64),
+}
+
+const INSERT_DISMISSED_ITEM: &str = r#"
+insert or ignore into dismissed_item (fan_id, item_id) values (?, ?)"#;
+
+const INSERT_DISMISSED_BAND: &str = r#"
+insert or ignore into dismissed_band (fan_id, band_id) values (?, ?)"#;
+
+const SELECT_BAND_EXISTS: &str = r#"
+select exists(select 1 from item where band_id = ?)"#;
+
+fn band_exists(db: &Connection, band_id: i64) -> Result<bool, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_BAND_EXISTS)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query_row([band_id], |row| row.get(0))
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+// Only items and bands in the database can be dismissed
+pub fn dismiss(db: &Connection, fan_id: i64, dismissal: Dismissal) -> Result<(), Error> {
+    let (exists, query, id) = match dismissal {
+        Dismissal::Item(item_id) => (
+            items::item_exists(db, item_id)?,
+            INSERT_DISMISSED_ITEM,
+            item_id,
+        ),
+        Dismissal::Band(band_id) => (band_exists(db, band_id)?, INSERT_DISMISSED_BAND, band_id),
+    };
+    ensure!(exists, NotFoundSnafu);
+    let mut stmt = db.prepare_cached(query).context(DbPrepareSnafu)?;
+    stmt.execute((fan_id, id)).context(DbWriteSnafu)?;
+    Ok(())
+}
+
+const DELETE_DISMISSED_ITEM: &str = r#"
+delete from dismissed_item where fan_id = ? and item_id = ?"#;
+
+const DELETE_DISMISSED_BAND: &str = r#"
+delete from dismissed_band where fan_id = ? and band_id = ?"#;
+
+pub fn undismiss(db: &Connection, fan_id: i64, dismissal: Dismissal) -> Result<(), Error> {
+    let (query, id) = match dismissal {
+        Dismissal::Item(item_id) => (DELETE_DISMISSED_ITEM, item_id),
+        Dismissal::Band(band_id) => (DELETE_DISMISSED_BAND, band_id),
+    };
+    let mut stmt = db.prepare_cached(query).context(DbPrepareSnafu)?;
+    stmt.execute((fan_id, id)).context(DbWriteSnafu)?;
+    Ok(())
+}
+
+fn get_ids(db: &Connection, query: &str, fan_id: i64) -> Result<HashSet<i64>, Error> {
+    let mut stmt = db.prepare_cached(query).context(DbPrepareSnafu)?;
+    let result = stmt
+        .query([fan_id])
+        .context(DbReadSnafu)?
+        .map(|row| row.get(0))
+        .collect()
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+const SELECT_DISMISSED_ITEMS: &str = r#"
+select item_id from dismissed_item where fan_id = ?"#;
+
+pub fn get_dismissed_items(db: &Connection, fan_id: i64) -> Result<HashSet<i64>, Error> {
+    get_ids(db, SELECT_DISMISSED_ITEMS, fan_id)
+}
+
+const SELECT_DISMISSED_BANDS: &str = r#"
+select band_id from dismissed_band where fan_id = ?"#;
+
+pub fn get_dismissed_bands(db: &Connection, fan_id: i64) -> Result<HashSet<i64>, Error> {
+    get_ids(db, SELECT_DISMISSED_BANDS, fan_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::{
+        get_user_recommendations, RankingCache, RankingParams, RecommendationFilter,
+    };
+    use crate::recommenders::Strategy;
+    use crate::store::test_pool;
+
+    #[test]
+    fn dismissed_items_and_bands_are_not_recommended() {
+        let store = test_pool("dismissals");
+        let conn = store.get().unwrap();
+        conn.execute_batch(
+            "insert into collector values (1, 'a', 'A', null, 0), (2, 'b', 'B', null, 0);
+            with recursive n(x) as (select 1 union all select x + 1 from n where x < 6)
+            insert into item select x, 'album', '', '', x, '', null, 0, 0 from n;
+            update item set band_id = 4 where item_id = 5;
+            insert into collects values (1, 1), (1, 2), (2, 1), (2, 2), (2, 3), (2, 4), (2, 5),
+                (2, 6);",
+        )
+        .unwrap();
+        let params = RankingParams::new(Strategy::Overlap, 2.0, 0.0, false);
+        // the ranking is cached, dismissals still apply right away
+        let cache = RankingCache::default();
+        let recommended = || {
+            let filter = RecommendationFilter::default();
+            get_user_recommendations(&store, &cache, "a", &params, &filter, 0, 10)
+                .unwrap()
+                .items
+                .into_iter()
+                .map(|item| item.item_id)
+                .collect::<HashSet<_>>()
+        };
+        assert_eq!(recommended(), HashSet::from([3, 4, 5, 6]));
+        dismiss(&conn, 1, Dismissal::Item(3)).unwrap();
+        dismiss(&conn, 1, Dismissal::Item(3)).unwrap();
+        dismiss(&conn, 1, Dismissal::Band(4)).unwrap();
+        assert_eq!(recommended(), HashSet::from([6]));
+        undismiss(&conn, 1, Dismissal::Band(4)).unwrap();
+        assert_eq!(recommended(), HashSet::from([4, 5, 6]));
+        assert_eq!(get_dismissed_items(&conn, 1).unwrap(), HashSet::from([3]));
+        assert!(get_dismissed_items(&conn, 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_items_and_bands_are_not_found() {
+        let store = test_pool("dismissals_unknown");
+        let conn = store.get().unwrap();
+        conn.execute_batch(
+            "insert into collector values (1, 'a', 'A', null, 0);
+            insert into item values (1, 'album', '', '', 2, '', null, 0, 0);",
+        )
+        .unwrap();
+        assert!(matches!(
+            dismiss(&conn, 1, Dismissal::Item(2)),
+            Err(Error::NotFoundError)
+        ));
+        assert!(matches!(
+            dismiss(&conn, 1, Dismissal::Band(1)),
+            Err(Error::NotFoundError)
+        ));
+        dismiss(&conn, 1, Dismissal::Item(1)).unwrap();
+        dismiss(&conn, 1, Dismissal::Band(2)).unwrap();
+    }
+}
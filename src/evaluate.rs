@@ -0,0 +1,234 @@
+use crate::analyze::{get_relevant_users, rank_candidates};
+use crate::recommenders::Strategy;
+use crate::{
+    DbPoolSnafu, DbPrepareSnafu, DbReadSnafu, Error, InvalidEvaluationSnafu, NotFoundSnafu,
+};
+use fallible_iterator::FallibleIterator;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rusqlite::Connection;
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone)]
+pub struct EvaluationConfig {
+    /// Number of collectors to sample
+    pub users: usize,
+    /// Fraction of each sampled collection to hide
+    pub hidden_fraction: f64,
+    /// Number of recommendations to score
+    pub k: usize,
+    /// Seed for sampling collectors and hidden items
+    pub seed: u64,
+    pub strategy: Strategy,
+    pub similar_boost: f64,
+    pub popularity_penalty: f64,
+}
+
+// Collectors with fewer items don't leave enough to recommend from after hiding
+const MIN_COLLECTION_SIZE: i64 = 5;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct EvaluationReport {
+    pub strategy: Strategy,
+    pub similar_boost: f64,
+    pub popularity_penalty: f64,
+    pub hidden_fraction: f64,
+    pub k: usize,
+    pub seed: u64,
+    pub users: usize,
+    pub precision: f64,
+    pub recall: f64,
+    pub ndcg: f64,
+    pub coverage: f64,
+}
+
+const SELECT_CANDIDATE_COLLECTORS: &str = r#"
+select username from collector
+join collects using (fan_id)
+group by fan_id
+having count(*) >= ?
+order by fan_id asc"#;
+
+fn get_candidate_collectors(db: &Connection) -> Result<Vec<String>, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_CANDIDATE_COLLECTORS)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query([MIN_COLLECTION_SIZE])
+        .context(DbReadSnafu)?
+        .map(|row| row.get(0))
+        .collect()
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+const SELECT_CATALOG_SIZE: &str = r#"
+select count(*) from item"#;
+
+fn get_catalog_size(db: &Connection) -> Result<usize, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_CATALOG_SIZE)
+        .context(DbPrepareSnafu)?;
+    let result = stmt.query_row([], |row| row.get(0)).context(DbReadSnafu)?;
+    Ok(result)
+}
+
+fn ndcg(ranked: &[i64], hidden: &HashSet<i64>, k: usize) -> f64 {
+    let dcg: f64 = ranked
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| hidden.contains(item))
+        .map(|(i, _)| 1.0 / (i as f64 + 2.0).log2())
+        .sum();
+    let ideal: f64 = (0..hidden.len().min(k))
+        .map(|i| 1.0 / (i as f64 + 2.0).log2())
+        .sum();
+    dcg / ideal
+}
+
+pub fn evaluate(
+    db: &Pool<SqliteConnectionManager>,
+    config: &EvaluationConfig,
+) -> Result<EvaluationReport, Error> {
+    ensure!(
+        config.k > 0,
+        InvalidEvaluationSnafu {
+            reason: "k has to be at least 1"
+        }
+    );
+    ensure!(
+        config.hidden_fraction > 0.0 && config.hidden_fraction < 1.0,
+        InvalidEvaluationSnafu {
+            reason: "the hidden fraction has to be between 0 and 1"
+        }
+    );
+    let conn = db.get().context(DbPoolSnafu)?;
+    let recommender = config
+        .strategy
+        .recommender(config.similar_boost, config.popularity_penalty);
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut collectors = get_candidate_collectors(&conn)?;
+    collectors.shuffle(&mut rng);
+    let mut users = 0;
+    let (mut precision, mut recall, mut ndcg_sum) = (0.0, 0.0, 0.0);
+    let mut recommended = HashSet::new();
+    for username in collectors.iter().take(config.users) {
+        let mut neighbours = get_relevant_users(&conn, username, false)?;
+        let fan_id =
+            crate::collectors::get_fan_id_for_username(&conn, username)?.context(NotFoundSnafu)?;
+        let mut collection = neighbours
+            .remove(&fan_id)
+            .unwrap_or_default()
+            .into_iter()
+            .collect::<Vec<_>>();
+        collection.sort_unstable();
+        collection.shuffle(&mut rng);
+        let hidden_count = ((collection.len() as f64 * config.hidden_fraction).round() as usize)
+            .clamp(1, collection.len() - 1);
+        let hidden = collection[..hidden_count]
+            .iter()
+            .copied()
+            .collect::<HashSet<_>>();
+        let visible = collection[hidden_count..]
+            .iter()
+            .copied()
+            .collect::<HashSet<_>>();
+        // same neighbourhood the live query would select, had the hidden items never been crawled
+        neighbours.retain(|_, user| user.intersection(&visible).count() > 1);
+        let ranked = rank_candidates(
+            &conn,
+            fan_id,
+            &visible,
+            &neighbours,
+            recommender.as_ref(),
+            config.popularity_penalty,
+        )?
+        .into_iter()
+        .take(config.k)
+        .map(|(item_id, _)| item_id)
+        .collect::<Vec<_>>();
+        let hits = ranked.iter().filter(|item| hidden.contains(item)).count();
+        precision += hits as f64 / config.k as f64;
+        recall += hits as f64 / hidden.len() as f64;
+        ndcg_sum += ndcg(&ranked, &hidden, config.k);
+        recommended.extend(ranked);
+        users += 1;
+    }
+    let catalog_size = get_catalog_size(&conn)?.max(1);
+    let users_divisor = users.max(1) as f64;
+    Ok(EvaluationReport {
+        strategy: config.strategy,
+        similar_boost: config.similar_boost,
+        popularity_penalty: config.popularity_penalty,
+        hidden_fraction: config.hidden_fraction,
+        k: config.k,
+        seed: config.seed,
+        users,
+        precision: precision / users_divisor,
+        recall: recall / users_divisor,
+        ndcg: ndcg_sum / users_divisor,
+        coverage: recommended.len() as f64 / catalog_size as f64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::test_pool;
+
+    #[test]
+    fn ndcg_rewards_early_hits() {
+        let hidden = HashSet::from([1, 2]);
+        assert_eq!(ndcg(&[1, 2, 3], &hidden, 3), 1.0);
+        let late = ndcg(&[3, 1, 2], &hidden, 3);
+        assert!(late > 0.0 && late < 1.0);
+        assert_eq!(ndcg(&[3, 4], &hidden, 2), 0.0);
+    }
+
+    #[test]
+    fn finds_hidden_items_of_identical_collections() {
+        let store = test_pool("evaluate");
+        let conn = store.get().unwrap();
+        conn.execute_batch(
+            "with recursive n(x) as (select 1 union all select x + 1 from n where x < 10)
+            insert into item select x, 'album', '', '', x, '', null, 0, 0 from n;
+            insert into collector values (1, 'a', '', null, 0), (2, 'b', '', null, 0),
+                (3, 'c', '', null, 0);
+            insert into collects select fan_id, item_id from collector, item where item_id <= 5;",
+        )
+        .unwrap();
+        let config = EvaluationConfig {
+            users: 10,
+            hidden_fraction: 0.2,
+            k: 1,
+            seed: 1,
+            strategy: Strategy::Overlap,
+            similar_boost: 2.0,
+            popularity_penalty: 0.0,
+        };
+        // one of five items is hidden, and the only candidate the neighbours can suggest
+        let report = evaluate(&store, &config).unwrap();
+        assert_eq!(report.users, 3);
+        assert_eq!(report.precision, 1.0);
+        assert_eq!(report.recall, 1.0);
+        assert_eq!(report.ndcg, 1.0);
+        assert!(report.coverage > 0.0 && report.coverage <= 0.3);
+
+        for (k, hidden_fraction) in [(0, 0.2), (1, 0.0), (1, 1.0)] {
+            let config = EvaluationConfig {
+                k,
+                hidden_fraction,
+                ..config.clone()
+            };
+            assert!(matches!(
+                evaluate(&store, &config),
+                Err(Error::InvalidEvaluationError { .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,525 @@
+use crate::{CsvSnafu, DbPrepareSnafu, DbReadSnafu, Error, IoSnafu, MissingSaltSnafu};
+use fallible_iterator::FallibleIterator;
+use rusqlite::types::Value;
+use rusqlite::{Connection, Rows};
+use serde::Deserialize;
+use serde_json::Map;
+use sha2::{Digest, Sha256};
+use snafu::{ensure, ResultExt};
+use std::io::Write;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "server", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportTable {
+    Item,
+    Collector,
+    Collects,
+    CollectedBy,
+    Wishes,
+    CollectionHistory,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "server", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Jsonl,
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "application/jsonl",
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    // hash usernames and fan ids, drop display names and tokens
+    pub anonymize: bool,
+    // mixed into the hashes, so they can't be reversed by hashing known usernames or fan ids.
+    // Required when anonymizing, and has to stay the same for all tables of one export
+    pub salt: String,
+}
+
+const SELECT_ITEMS: &str = r#"
+select * from item order by item_id"#;
+
+const SELECT_COLLECTORS: &str = r#"
+select * from collector order by fan_id"#;
+
+const SELECT_COLLECTS: &str = r#"
+select * from collects order by fan_id, item_id"#;
+
+const SELECT_COLLECTED_BY: &str = r#"
+select * from collected_by order by item_id, fan_id"#;
+
+const SELECT_WISHES: &str = r#"
+select * from wishes order by fan_id, item_id"#;
+
+const SELECT_COLLECTION_HISTORY: &str = r#"
+select * from collection_history order by fan_id, item_id"#;
+
+impl ExportTable {
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportTable::Item => "item",
+            ExportTable::Collector => "collector",
+            ExportTable::Collects => "collects",
+            ExportTable::CollectedBy => "collected_by",
+            ExportTable::Wishes => "wishes",
+            ExportTable::CollectionHistory => "collection_history",
+        }
+    }
+
+    fn query(self) -> &'static str {
+        match self {
+            ExportTable::Item => SELECT_ITEMS,
+            ExportTable::Collector => SELECT_COLLECTORS,
+            ExportTable::Collects => SELECT_COLLECTS,
+            ExportTable::CollectedBy => SELECT_COLLECTED_BY,
+            ExportTable::Wishes => SELECT_WISHES,
+            ExportTable::CollectionHistory => SELECT_COLLECTION_HISTORY,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Integer,
+    Real,
+    Text,
+}
+
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    pub column_type: ColumnType,
+    pub not_null: bool,
+}
+
+const SELECT_COLUMNS: &str = r#"
+select name, type, "notnull" from pragma_table_info(?) order by cid"#;
+
+// In the same order as `select *`
+pub fn table_columns(db: &Connection, table: ExportTable) -> Result<Vec<Column>, Error> {
+    let mut stmt = db.prepare_cached(SELECT_COLUMNS).context(DbPrepareSnafu)?;
+    let result = stmt
+        .query([table.name()])
+        .context(DbReadSnafu)?
+        .map(|row| {
+            let column_type: String = row.get(1)?;
+            Ok(Column {
+                name: row.get(0)?,
+                column_type: match column_type.to_lowercase().as_str() {
+                    "integer" => ColumnType::Integer,
+                    "real" => ColumnType::Real,
+                    _ => ColumnType::Text,
+                },
+                not_null: row.get(2)?,
+            })
+        })
+        .collect()
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Anonymization {
+    Keep,
+    Hash,
+    Pseudonym,
+    Drop,
+}
+
+fn anonymization(table: ExportTable, column: &str) -> Anonymization {
+    match (table, column) {
+        (ExportTable::Collector, "username") => Anonymization::Hash,
+        (ExportTable::Collector, "name" | "token") => Anonymization::Drop,
+        // fan ids are public on bandcamp, so they identify a collector as well as the username
+        (_, "fan_id") => Anonymization::Pseudonym,
+        _ => Anonymization::Keep,
+    }
+}
+
+fn hash_username(salt: &str, username: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(salt)
+        .chain_update(username)
+        .finalize();
+    // 128 bits are plenty to stay unique
+    digest[..16]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+// Stays an integer so anonymized exports can be imported, and is the same in every table
+fn pseudonymize_id(salt: &str, id: i64) -> i64 {
+    let digest = Sha256::new()
+        .chain_update(salt)
+        .chain_update(id.to_le_bytes())
+        .finalize();
+    i64::from_le_bytes(digest[..8].try_into().unwrap()) & i64::MAX
+}
+
+trait RowWriter {
+    fn write_row(&mut self, row: Vec<Value>) -> Result<(), Error>;
+
+    fn finish(self) -> Result<(), Error>;
+}
+
+struct JsonWriter<W: Write> {
+    out: W,
+    columns: Vec<String>,
+}
+
+fn to_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Null | Value::Blob(_) => serde_json::Value::Null,
+        Value::Integer(value) => value.into(),
+        Value::Real(value) => value.into(),
+        Value::Text(value) => value.into(),
+    }
+}
+
+impl<W: Write> RowWriter for JsonWriter<W> {
+    fn write_row(&mut self, row: Vec<Value>) -> Result<(), Error> {
+        let object = self
+            .columns
+            .iter()
+            .cloned()
+            .zip(row.into_iter().map(to_json))
+            .collect::<Map<_, _>>();
+        writeln!(self.out, "{}", serde_json::Value::Object(object)).context(IoSnafu)
+    }
+
+    fn finish(mut self) -> Result<(), Error> {
+        self.out.flush().context(IoSnafu)
+    }
+}
+
+struct CsvWriter<W: Write> {
+    out: csv::Writer<W>,
+}
+
+impl<W: Write> CsvWriter<W> {
+    fn new(out: W, columns: &[Column]) -> Result<Self, Error> {
+        let mut out = csv::Writer::from_writer(out);
+        out.write_record(columns.iter().map(|column| &column.name))
+            .context(CsvSnafu)?;
+        Ok(CsvWriter { out })
+    }
+}
+
+impl<W: Write> RowWriter for CsvWriter<W> {
+    // null and the empty string look the same, the import tells them apart by the schema
+    fn write_row(&mut self, row: Vec<Value>) -> Result<(), Error> {
+        let record = row.into_iter().map(|value| match value {
+            Value::Null | Value::Blob(_) => String::new(),
+            Value::Integer(value) => value.to_string(),
+            Value::Real(value) => value.to_string(),
+            Value::Text(value) => value,
+        });
+        self.out.write_record(record).context(CsvSnafu)
+    }
+
+    fn finish(mut self) -> Result<(), Error> {
+        self.out.flush().context(IoSnafu)
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_writer {
+    use super::{Column, ColumnType, RowWriter};
+    use crate::{ArrowSnafu, Error, IoSnafu, ParquetSnafu};
+    use arrow_array::builder::{Float64Builder, Int64Builder, StringBuilder};
+    use arrow_array::{ArrayRef, RecordBatch};
+    use arrow_schema::{DataType, Field, Schema, SchemaRef};
+    use parquet::arrow::ArrowWriter;
+    use rusqlite::types::Value;
+    use snafu::ResultExt;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    // Rows per row group
+    const BATCH_SIZE: usize = 64 * 1024;
+
+    pub struct ParquetWriter<W: Write + Send> {
+        writer: ArrowWriter<W>,
+        schema: SchemaRef,
+        column_types: Vec<ColumnType>,
+        rows: Vec<Vec<Value>>,
+    }
+
+    fn build_array<'a>(
+        column_type: ColumnType,
+        values: impl Iterator<Item = &'a Value>,
+    ) -> ArrayRef {
+        match column_type {
+            ColumnType::Integer => {
+                let mut builder = Int64Builder::new();
+                for value in values {
+                    match value {
+                        Value::Integer(value) => builder.append_value(*value),
+                        _ => builder.append_null(),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnType::Real => {
+                let mut builder = Float64Builder::new();
+                for value in values {
+                    match value {
+                        Value::Real(value) => builder.append_value(*value),
+                        Value::Integer(value) => builder.append_value(*value as f64),
+                        _ => builder.append_null(),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnType::Text => {
+                let mut builder = StringBuilder::new();
+                for value in values {
+                    match value {
+                        Value::Text(value) => builder.append_value(value),
+                        _ => builder.append_null(),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+        }
+    }
+
+    impl<W: Write + Send> ParquetWriter<W> {
+        pub fn new(out: W, columns: &[Column]) -> Result<Self, Error> {
+            let fields = columns
+                .iter()
+                .map(|column| {
+                    let data_type = match column.column_type {
+                        ColumnType::Integer => DataType::Int64,
+                        ColumnType::Real => DataType::Float64,
+                        ColumnType::Text => DataType::Utf8,
+                    };
+                    Field::new(&column.name, data_type, !column.not_null)
+                })
+                .collect::<Vec<_>>();
+            let schema = Arc::new(Schema::new(fields));
+            let writer = ArrowWriter::try_new(out, schema.clone(), None).context(ParquetSnafu)?;
+            Ok(ParquetWriter {
+                writer,
+                schema,
+                column_types: columns.iter().map(|column| column.column_type).collect(),
+                rows: Vec::with_capacity(BATCH_SIZE),
+            })
+        }
+
+        fn write_batch(&mut self) -> Result<(), Error> {
+            let arrays = self
+                .column_types
+                .iter()
+                .enumerate()
+                .map(|(index, column_type)| {
+                    build_array(*column_type, self.rows.iter().map(|row| &row[index]))
+                })
+                .collect();
+            let batch = RecordBatch::try_new(self.schema.clone(), arrays).context(ArrowSnafu)?;
+            self.writer.write(&batch).context(ParquetSnafu)?;
+            self.rows.clear();
+            Ok(())
+        }
+    }
+
+    impl<W: Write + Send> RowWriter for ParquetWriter<W> {
+        fn write_row(&mut self, row: Vec<Value>) -> Result<(), Error> {
+            self.rows.push(row);
+            if self.rows.len() >= BATCH_SIZE {
+                self.write_batch()?;
+            }
+            Ok(())
+        }
+
+        fn finish(mut self) -> Result<(), Error> {
+            if !self.rows.is_empty() {
+                self.write_batch()?;
+            }
+            // writes the footer
+            let mut out = self.writer.into_inner().context(ParquetSnafu)?;
+            out.flush().context(IoSnafu)
+        }
+    }
+}
+
+fn write_rows(
+    mut rows: Rows,
+    columns: &[(usize, Anonymization)],
+    salt: &str,
+    mut writer: impl RowWriter,
+) -> Result<usize, Error> {
+    let mut count = 0;
+    while let Some(row) = rows.next().context(DbReadSnafu)? {
+        let values = columns
+            .iter()
+            .map(|(index, anonymization)| {
+                let value = row.get(*index)?;
+                Ok(match (anonymization, value) {
+                    (Anonymization::Hash, Value::Text(value)) => {
+                        Value::Text(hash_username(salt, &value))
+                    }
+                    (Anonymization::Pseudonym, Value::Integer(value)) => {
+                        Value::Integer(pseudonymize_id(salt, value))
+                    }
+                    (_, value) => value,
+                })
+            })
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context(DbReadSnafu)?;
+        writer.write_row(values)?;
+        count += 1;
+    }
+    writer.finish()?;
+    Ok(count)
+}
+
+// Streams the table to out in the given format, returns the number of rows
+pub fn export_table(
+    db: &Connection,
+    table: ExportTable,
+    options: &ExportOptions,
+    out: impl Write + Send,
+) -> Result<usize, Error> {
+    ensure!(
+        !options.anonymize || !options.salt.is_empty(),
+        MissingSaltSnafu
+    );
+    let mut selected = Vec::new();
+    let mut columns = Vec::new();
+    for (index, column) in table_columns(db, table)?.into_iter().enumerate() {
+        let anonymization = if options.anonymize {
+            anonymization(table, &column.name)
+        } else {
+            Anonymization::Keep
+        };
+        if anonymization != Anonymization::Drop {
+            selected.push((index, anonymization));
+            columns.push(column);
+        }
+    }
+    let mut stmt = db.prepare_cached(table.query()).context(DbPrepareSnafu)?;
+    let rows = stmt.query([]).context(DbReadSnafu)?;
+    let salt = &options.salt;
+    match options.format {
+        ExportFormat::Jsonl => {
+            let writer = JsonWriter {
+                out,
+                columns: columns.into_iter().map(|column| column.name).collect(),
+            };
+            write_rows(rows, &selected, salt, writer)
+        }
+        ExportFormat::Csv => write_rows(rows, &selected, salt, CsvWriter::new(out, &columns)?),
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => {
+            let writer = parquet_writer::ParquetWriter::new(out, &columns)?;
+            write_rows(rows, &selected, salt, writer)
+        }
+        #[cfg(not(feature = "parquet"))]
+        ExportFormat::Parquet => crate::UnsupportedFormatSnafu { format: "parquet" }.fail(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::test_pool;
+
+    #[test]
+    fn exports_rows_as_json_lines() {
+        let db = test_pool("export");
+        let conn = db.get().unwrap();
+        conn.execute_batch(
+            "insert into collector values (1, 'a', 'A \"quoted\"', null, 0), (2, 'b', 'B', 't', 5);",
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        let options = ExportOptions::default();
+        assert_eq!(
+            export_table(&conn, ExportTable::Collector, &options, &mut out).unwrap(),
+            2
+        );
+        let lines = String::from_utf8(out).unwrap();
+        let rows = lines
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rows[0]["name"], "A \"quoted\"");
+        assert_eq!(rows[0]["token"], serde_json::Value::Null);
+        assert_eq!(rows[1]["last_updated"], 5);
+    }
+
+    #[test]
+    fn anonymizes_collectors() {
+        let db = test_pool("export_anonymized");
+        let conn = db.get().unwrap();
+        conn.execute_batch(
+            "insert into collector values (1, 'a', 'Real Name', 'token', 0);
+            insert into item values (7, 'album', 'Title', '', 1, 'Band', null, 0, 0);
+            insert into collects values (1, 7);
+            insert into collected_by values (7, 1);
+            insert into wishes values (1, 7);",
+        )
+        .unwrap();
+        let mut options = ExportOptions {
+            format: ExportFormat::Csv,
+            anonymize: true,
+            salt: String::new(),
+        };
+        assert!(matches!(
+            export_table(&conn, ExportTable::Collector, &options, Vec::new()),
+            Err(Error::MissingSaltError)
+        ));
+        options.salt = "salt".to_string();
+        let mut out = Vec::new();
+        export_table(&conn, ExportTable::Collector, &options, &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let fan_id = pseudonymize_id("salt", 1);
+        let expected = format!(
+            "fan_id,username,last_updated\n{fan_id},{},0\n",
+            hash_username("salt", "a")
+        );
+        assert_eq!(csv, expected);
+        assert_ne!(hash_username("salt", "a"), hash_username("other", "a"));
+        assert_ne!(fan_id, pseudonymize_id("other", 1));
+        // the same pseudonym in every table referencing the collector
+        for (table, expected) in [
+            (
+                ExportTable::Collects,
+                format!("fan_id,item_id\n{fan_id},7\n"),
+            ),
+            (
+                ExportTable::CollectedBy,
+                format!("item_id,fan_id\n7,{fan_id}\n"),
+            ),
+            (ExportTable::Wishes, format!("fan_id,item_id\n{fan_id},7\n")),
+        ] {
+            let mut out = Vec::new();
+            export_table(&conn, table, &options, &mut out).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), expected);
+        }
+    }
+}
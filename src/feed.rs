@@ -0,0 +1,119 @@
+use crate::freshness;
+use crate::types::FeedEntry;
+use chrono::{DateTime, SecondsFormat};
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn timestamp(value: i64) -> String {
+    DateTime::from_timestamp(value, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// Atom version of the neighbours feed, so it can be followed in a feed reader
+pub fn render_atom(username: &str, self_url: &str, entries: &[FeedEntry]) -> String {
+    let updated = entries
+        .iter()
+        .map(|entry| entry.purchased_at)
+        .max()
+        .unwrap_or_else(freshness::now);
+    let mut result = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    result.push_str("\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    result.push_str(&format!(
+        "<id>urn:bandcamp-recommendations:feed:{}</id>\n",
+        escape(username)
+    ));
+    result.push_str(&format!(
+        "<title>Recently bought by neighbours of {}</title>\n",
+        escape(username)
+    ));
+    result.push_str(&format!(
+        "<link rel=\"self\" href=\"{}\"/>\n",
+        escape(self_url)
+    ));
+    result.push_str(&format!("<updated>{}</updated>\n", timestamp(updated)));
+    result.push_str("<author><name>bandcamp_recommendations</name></author>\n");
+    for entry in entries {
+        let item = &entry.item;
+        let bought_by = item
+            .explanation
+            .as_ref()
+            .map(|explanation| explanation.collectors.join(", "))
+            .unwrap_or_default();
+        result.push_str("<entry>\n");
+        result.push_str(&format!(
+            "<id>urn:bandcamp-recommendations:item:{}</id>\n",
+            item.item_id
+        ));
+        result.push_str(&format!(
+            "<title>{} by {}</title>\n",
+            escape(&item.item_title),
+            escape(&item.band_name)
+        ));
+        result.push_str(&format!("<link href=\"{}\"/>\n", escape(&item.item_url)));
+        result.push_str(&format!(
+            "<updated>{}</updated>\n",
+            timestamp(entry.purchased_at)
+        ));
+        result.push_str(&format!(
+            "<summary>Collected by {}</summary>\n",
+            escape(&bought_by)
+        ));
+        result.push_str("</entry>\n");
+    }
+    result.push_str("</feed>\n");
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::get_user_feed;
+    use crate::store::test_pool;
+
+    #[test]
+    fn ranks_recent_purchases_of_neighbours() {
+        let db = test_pool("feed");
+        let conn = db.get().unwrap();
+        let now = freshness::now();
+        conn.execute_batch(
+            "insert into collector values (1, 'me', 'Me', null, 0), (2, 'close', 'Close', null, 0),
+                (3, 'far', 'Far', null, 0);
+            insert into item values (1, 'album', 'A', '', 1, 'Band', null, 0, 0),
+                (2, 'album', 'B', '', 1, 'Band', null, 0, 0),
+                (3, 'album', 'C', '', 1, 'Band', null, 0, 0),
+                (7, 'album', 'Item <7>', 'https://b.bandcamp.com/7', 2, 'Band & Co', null, 0, 0),
+                (8, 'album', 'Item <8>', 'https://b.bandcamp.com/8', 3, 'Band & Co', null, 0, 0),
+                (9, 'album', 'Item <9>', 'https://b.bandcamp.com/9', 4, 'Band & Co', null, 0, 0);
+            insert into collects values (1, 1), (1, 2), (1, 3), (2, 1), (2, 2), (2, 3), (3, 1),
+                (3, 2), (2, 7), (2, 8), (3, 7), (3, 9);",
+        )
+        .unwrap();
+        for (fan_id, item_id, days_ago) in [(2, 7, 2), (3, 7, 2), (2, 8, 5), (3, 9, 1), (2, 1, 90)]
+        {
+            conn.execute(
+                "insert into collection_history values (?1, ?2, ?3, 0, ?4, null)",
+                (fan_id, item_id, now - days_ago * freshness::DAY, now),
+            )
+            .unwrap();
+        }
+        let entries = get_user_feed(&db, "me", 30, 10).unwrap();
+        let ids = entries
+            .iter()
+            .map(|entry| entry.item.item_id)
+            .collect::<Vec<_>>();
+        // 7 was bought by both, and the closer neighbour's purchase of 8 outweighs the newer 9
+        assert_eq!(ids, vec![7, 8, 9]);
+        assert_eq!(entries[0].purchased_at, now - 2 * freshness::DAY);
+        let atom = render_atom("me", "http://localhost/api/feed.atom?username=me", &entries);
+        assert!(atom.contains("<title>Item &lt;7&gt; by Band &amp; Co</title>"));
+        assert_eq!(atom.matches("<entry>").count(), 3);
+    }
+}
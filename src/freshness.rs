@@ -0,0 +1,171 @@
+use crate::{DbPrepareSnafu, DbWriteSnafu, Error};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::Connection;
+use snafu::ResultExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DAY: i64 = 24 * 60 * 60;
+
+// Decides when collections and collected_by lists are refetched. The queries use it through the
+// collector_is_stale and item_is_stale sql functions, which are registered on every connection.
+#[derive(Debug, Clone, Copy)]
+pub struct FreshnessPolicy {
+    pub collection_max_age: i64,        // seconds
+    pub active_collection_max_age: i64, // seconds
+    // collectors that bought at least this many items between their last two refreshes are active
+    pub active_new_items: i64,
+    pub collected_by_max_age: i64, // seconds
+}
+
+impl Default for FreshnessPolicy {
+    fn default() -> Self {
+        FreshnessPolicy {
+            collection_max_age: 30 * DAY,
+            active_collection_max_age: 7 * DAY,
+            active_new_items: 5,
+            collected_by_max_age: 30 * DAY,
+        }
+    }
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+// The background refreshes never fetch anything again within a day, whatever the policy says.
+// Users can still ask for a refresh of their own target with invalidate_for_user
+const MIN_REFRESH_AGE: i64 = DAY;
+
+impl FreshnessPolicy {
+    pub fn collector_is_stale(&self, last_updated: i64, new_items: i64) -> bool {
+        let max_age = if new_items >= self.active_new_items {
+            self.active_collection_max_age.min(self.collection_max_age)
+        } else {
+            self.collection_max_age
+        };
+        now() - last_updated > max_age.max(MIN_REFRESH_AGE)
+    }
+
+    pub fn item_is_stale(&self, last_updated: i64) -> bool {
+        now() - last_updated > self.collected_by_max_age.max(MIN_REFRESH_AGE)
+    }
+}
+
+pub fn register_functions(db: &Connection, policy: FreshnessPolicy) -> rusqlite::Result<()> {
+    // collector_is_stale(last_updated, new_items), new_items may be null if unknown
+    db.create_scalar_function(
+        "collector_is_stale",
+        2,
+        FunctionFlags::SQLITE_UTF8,
+        move |ctx| {
+            let last_updated: i64 = ctx.get(0)?;
+            let new_items: Option<i64> = ctx.get(1)?;
+            Ok(policy.collector_is_stale(last_updated, new_items.unwrap_or(0)))
+        },
+    )?;
+    db.create_scalar_function("item_is_stale", 1, FunctionFlags::SQLITE_UTF8, move |ctx| {
+        Ok(policy.item_is_stale(ctx.get(0)?))
+    })
+}
+
+#[cfg(feature = "workers")]
+const UPSERT_COLLECTOR_ACTIVITY: &str = r#"
+insert into collector_activity (fan_id, collection_size, new_items)
+select fan_id, count(*), 0 from collects
+where fan_id = (select fan_id from collector where username = ?)
+group by fan_id
+on conflict do update
+set new_items = max(excluded.collection_size - collection_size, 0),
+    collection_size = excluded.collection_size"#;
+
+#[cfg(feature = "workers")]
+pub fn update_collector_activity(db: &Connection, name: &str) -> Result<(), Error> {
+    let mut stmt = db
+        .prepare_cached(UPSERT_COLLECTOR_ACTIVITY)
+        .context(DbPrepareSnafu)?;
+    stmt.execute([name]).context(DbWriteSnafu)?;
+    Ok(())
+}
+
+const INVALIDATE_ITEMS: &str = r#"
+update item set last_updated = 0
+where item_id in (select item_id from collects where fan_id = ?1)
+and last_updated < unixepoch('now') - ?2"#;
+
+// same neighbours as the stage 2 requirements of a target
+const INVALIDATE_NEIGHBOURS: &str = r#"
+update collector set last_updated = 0
+where fan_id in (
+    select fan_id from collected_by
+    where item_id in (select item_id from collects where fan_id = ?1)
+    group by fan_id
+    having count(fan_id) > 1
+) and fan_id != ?1 and last_updated < unixepoch('now') - ?2"#;
+
+// Marks everything a target depends on as stale if it is older than max_age seconds
+pub fn invalidate_for_user(db: &Connection, fan_id: i64, max_age: i64) -> Result<(), Error> {
+    for query in [INVALIDATE_ITEMS, INVALIDATE_NEIGHBOURS] {
+        let mut stmt = db.prepare_cached(query).context(DbPrepareSnafu)?;
+        stmt.execute((fan_id, max_age)).context(DbWriteSnafu)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::test_pool;
+
+    #[test]
+    fn refreshes_active_collectors_sooner() {
+        let policy = FreshnessPolicy::default();
+        let ten_days_ago = now() - 10 * DAY;
+        assert!(!policy.collector_is_stale(ten_days_ago, 0));
+        assert!(policy.collector_is_stale(ten_days_ago, 5));
+        assert!(policy.collector_is_stale(0, 0));
+        assert!(!policy.item_is_stale(ten_days_ago));
+        assert!(policy.item_is_stale(now() - 31 * DAY));
+    }
+
+    #[test]
+    fn policies_refresh_nothing_younger_than_a_day() {
+        let policy = FreshnessPolicy {
+            collection_max_age: 0,
+            active_collection_max_age: 0,
+            active_new_items: 0,
+            collected_by_max_age: 0,
+        };
+        assert!(!policy.collector_is_stale(now() - 60, 0));
+        assert!(!policy.item_is_stale(now() - 60));
+        assert!(policy.item_is_stale(now() - 2 * DAY));
+    }
+
+    #[test]
+    fn explicit_refreshes_take_any_age() {
+        let store = test_pool("freshness");
+        let conn = store.get().unwrap();
+        let (recent, old) = (now() - 60, now() - 2 * DAY);
+        conn.execute_batch(&format!(
+            "insert into collector values (1, 'a', 'A', null, {recent});
+            insert into item values (1, 'album', '', '', 1, '', null, 0, {recent});
+            insert into item values (2, 'album', '', '', 1, '', null, 0, {old});
+            insert into collects values (1, 1), (1, 2);"
+        ))
+        .unwrap();
+        let updated = || {
+            conn.prepare("select last_updated from item order by item_id")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<Vec<i64>>>()
+                .unwrap()
+        };
+        invalidate_for_user(&conn, 1, DAY).unwrap();
+        assert_eq!(updated(), [recent, 0]);
+        invalidate_for_user(&conn, 1, 0).unwrap();
+        assert_eq!(updated(), [0, 0]);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::export::{table_columns, Column, ColumnType, ExportFormat, ExportTable};
+use crate::{
+    CsvSnafu, DbPrepareSnafu, DbWriteSnafu, Error, InvalidValueSnafu, IoSnafu, SerializationSnafu,
+    UnknownColumnSnafu,
+};
+use rusqlite::types::Value;
+use rusqlite::{CachedStatement, Connection, TransactionBehavior};
+use snafu::{OptionExt, ResultExt};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+// Inserts rows whose columns are in the order of the file, which may leave out nullable columns
+struct Insert<'a> {
+    stmt: CachedStatement<'a>,
+    columns: Vec<Column>,
+}
+
+impl<'a> Insert<'a> {
+    fn new(db: &'a Connection, table: ExportTable, header: &[String]) -> Result<Self, Error> {
+        let table_columns = table_columns(db, table)?;
+        let columns = header
+            .iter()
+            .map(|name| {
+                table_columns
+                    .iter()
+                    .find(|column| &column.name == name)
+                    .cloned()
+                    .context(UnknownColumnSnafu { column: name })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut names = header.to_vec();
+        let mut values = (1..=header.len())
+            .map(|index| format!("?{index}"))
+            .collect::<Vec<_>>();
+        // anonymized exports have no display names
+        let has_name = header.iter().any(|name| name == "name");
+        let username = header.iter().position(|name| name == "username");
+        if let (ExportTable::Collector, false, Some(index)) = (table, has_name, username) {
+            names.push("name".to_string());
+            values.push(format!("?{}", index + 1));
+        }
+        let query = format!(
+            "insert or ignore into {} ({}) values ({})",
+            table.name(),
+            names.join(", "),
+            values.join(", ")
+        );
+        let stmt = db.prepare_cached(&query).context(DbPrepareSnafu)?;
+        Ok(Insert { stmt, columns })
+    }
+
+    // Returns 1 if the row was new
+    fn row(&mut self, values: Vec<Value>) -> Result<usize, Error> {
+        let result = self
+            .stmt
+            .execute(rusqlite::params_from_iter(values))
+            .context(DbWriteSnafu)?;
+        Ok(result)
+    }
+}
+
+fn invalid(column: &Column, value: impl ToString) -> Error {
+    InvalidValueSnafu {
+        column: &column.name,
+        value: value.to_string(),
+    }
+    .build()
+}
+
+fn from_json(column: &Column, value: &serde_json::Value) -> Result<Value, Error> {
+    let result = match (column.column_type, value) {
+        (_, serde_json::Value::Null) => Value::Null,
+        (ColumnType::Integer, serde_json::Value::Number(number)) => {
+            Value::Integer(number.as_i64().ok_or_else(|| invalid(column, number))?)
+        }
+        (ColumnType::Real, serde_json::Value::Number(number)) => {
+            Value::Real(number.as_f64().ok_or_else(|| invalid(column, number))?)
+        }
+        (ColumnType::Text, serde_json::Value::String(value)) => Value::Text(value.clone()),
+        _ => return Err(invalid(column, value)),
+    };
+    Ok(result)
+}
+
+fn import_jsonl(db: &Connection, table: ExportTable, file: File) -> Result<usize, Error> {
+    let mut insert = None;
+    let mut count = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.context(IoSnafu)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let object: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&line).context(SerializationSnafu)?;
+        // the first row decides which columns are present
+        if insert.is_none() {
+            let header = object.keys().cloned().collect::<Vec<_>>();
+            insert = Some(Insert::new(db, table, &header)?);
+        }
+        let insert = insert.as_mut().unwrap();
+        let values = insert
+            .columns
+            .iter()
+            .map(|column| {
+                let value = object.get(&column.name).unwrap_or(&serde_json::Value::Null);
+                from_json(column, value)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        count += insert.row(values)?;
+    }
+    Ok(count)
+}
+
+// Empty fields are null, unless the column can't be
+fn from_csv(column: &Column, value: &str) -> Result<Value, Error> {
+    if value.is_empty() && !(column.not_null && column.column_type == ColumnType::Text) {
+        return Ok(Value::Null);
+    }
+    let result = match column.column_type {
+        ColumnType::Integer => Value::Integer(value.parse().map_err(|_| invalid(column, value))?),
+        ColumnType::Real => Value::Real(value.parse().map_err(|_| invalid(column, value))?),
+        ColumnType::Text => Value::Text(value.to_string()),
+    };
+    Ok(result)
+}
+
+fn import_csv(db: &Connection, table: ExportTable, file: File) -> Result<usize, Error> {
+    let mut reader = csv::Reader::from_reader(BufReader::new(file));
+    let header = reader
+        .headers()
+        .context(CsvSnafu)?
+        .iter()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    let mut insert = Insert::new(db, table, &header)?;
+    let mut count = 0;
+    for record in reader.records() {
+        let record = record.context(CsvSnafu)?;
+        let values = insert
+            .columns
+            .iter()
+            .zip(record.iter())
+            .map(|(column, value)| from_csv(column, value))
+            .collect::<Result<Vec<_>, _>>()?;
+        count += insert.row(values)?;
+    }
+    Ok(count)
+}
+
+#[cfg(feature = "parquet")]
+fn import_parquet(db: &Connection, table: ExportTable, file: File) -> Result<usize, Error> {
+    use crate::{ArrowSnafu, ParquetSnafu};
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, Int64Type};
+    use arrow_array::{Array, RecordBatchReader};
+    use arrow_schema::DataType;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+        .context(ParquetSnafu)?
+        .build()
+        .context(ParquetSnafu)?;
+    let header = reader
+        .schema()
+        .fields()
+        .iter()
+        .map(|field| field.name().clone())
+        .collect::<Vec<_>>();
+    let mut insert = Insert::new(db, table, &header)?;
+    let mut count = 0;
+    for batch in reader {
+        let batch = batch.context(ArrowSnafu)?;
+        for row in 0..batch.num_rows() {
+            let values = insert
+                .columns
+                .iter()
+                .zip(batch.columns())
+                .map(|(column, array)| {
+                    if array.is_null(row) {
+                        return Ok(Value::Null);
+                    }
+                    let value = match (column.column_type, array.data_type()) {
+                        (ColumnType::Integer, DataType::Int64) => {
+                            Value::Integer(array.as_primitive::<Int64Type>().value(row))
+                        }
+                        (ColumnType::Real, DataType::Float64) => {
+                            Value::Real(array.as_primitive::<Float64Type>().value(row))
+                        }
+                        (ColumnType::Text, DataType::Utf8) => {
+                            Value::Text(array.as_string::<i32>().value(row).to_string())
+                        }
+                        (_, data_type) => return Err(invalid(column, data_type)),
+                    };
+                    Ok(value)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            count += insert.row(values)?;
+        }
+    }
+    Ok(count)
+}
+
+// Reads a file written by export_table in one transaction and returns the number of new rows,
+// rows that already exist are kept.
+// Collectors and items have to be imported before the tables referencing them.
+pub fn import_table(
+    db: &mut Connection,
+    table: ExportTable,
+    format: ExportFormat,
+    path: &Path,
+) -> Result<usize, Error> {
+    let file = File::open(path).context(IoSnafu)?;
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .context(DbWriteSnafu)?;
+    let count = match format {
+        ExportFormat::Jsonl => import_jsonl(&tx, table, file)?,
+        ExportFormat::Csv => import_csv(&tx, table, file)?,
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => import_parquet(&tx, table, file)?,
+        #[cfg(not(feature = "parquet"))]
+        ExportFormat::Parquet => {
+            return crate::UnsupportedFormatSnafu { format: "parquet" }.fail();
+        }
+    };
+    tx.commit().context(DbWriteSnafu)?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::{export_table, ExportOptions};
+    use crate::store::test_pool;
+
+    #[test]
+    fn imports_exports_in_every_format() {
+        let source = test_pool("import_source");
+        let conn = source.get().unwrap();
+        conn.execute_batch(
+            "insert into collector values (1, 'a', 'A, \"quoted\"', null, 0), (2, 'b', 'B', 't', 5);
+            insert into item values (7, 'album', 'Title', '', 1, 'Band', null, 0, 0);
+            insert into collects values (1, 7), (2, 7);",
+        )
+        .unwrap();
+        let mut formats = vec![ExportFormat::Jsonl, ExportFormat::Csv];
+        if cfg!(feature = "parquet") {
+            formats.push(ExportFormat::Parquet);
+        }
+        for format in formats {
+            let target = test_pool(&format!("import_{}", format.extension()));
+            let mut target = target.get().unwrap();
+            let anonymize = format == ExportFormat::Csv;
+            for table in [
+                ExportTable::Collector,
+                ExportTable::Item,
+                ExportTable::Collects,
+            ] {
+                let path = std::env::temp_dir().join(format!(
+                    "bandcamp_recommendations_{}_{}.{}",
+                    std::process::id(),
+                    table.name(),
+                    format.extension()
+                ));
+                let options = ExportOptions {
+                    format,
+                    anonymize,
+                    salt: "salt".to_string(),
+                };
+                let file = File::create(&path).unwrap();
+                let exported = export_table(&conn, table, &options, file).unwrap();
+                let imported = import_table(&mut target, table, format, &path).unwrap();
+                assert_eq!(exported, imported, "{table:?} as {format:?}");
+            }
+            let (name, token, url): (String, Option<String>, String) = target
+                .query_row(
+                    "select name, collector.token, item_url from collector, item
+                    order by collector.last_updated limit 1",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .unwrap();
+            if format == ExportFormat::Csv {
+                // the display name is replaced by the hashed username
+                assert_eq!(name.len(), 32);
+            } else {
+                assert_eq!(name, "A, \"quoted\"");
+            }
+            assert_eq!(token, None);
+            assert_eq!(url, "");
+        }
+    }
+}
@@ -1,24 +1,43 @@
+#[cfg(feature = "workers")]
+use crate::client::BandcampClient;
+#[cfg(feature = "workers")]
 use crate::collectors::add_collector;
-use crate::types::{Collector, Item};
-use crate::{
-    DbPoolSnafu, DbPrepareSnafu, DbReadSnafu, DbResultSnafu, DbWriteSnafu, Error, NetworkSnafu,
-    PageSnafu, SerializationSnafu,
-};
-use lazy_static::lazy_static;
+#[cfg(feature = "server")]
+use crate::collectors::add_item;
+#[cfg(feature = "workers")]
+use crate::jobs;
+use crate::types::Item;
+#[cfg(feature = "server")]
+use crate::types::ItemType;
+#[cfg(feature = "workers")]
+use crate::types::{Collector, JobKind};
+#[cfg(feature = "workers")]
+use crate::{DbPoolSnafu, DbWriteSnafu, PageSnafu, SerializationSnafu};
+use crate::{DbPrepareSnafu, DbReadSnafu, DbResultSnafu, Error};
+#[cfg(feature = "workers")]
 use r2d2::Pool;
+#[cfg(feature = "workers")]
 use r2d2_sqlite::SqliteConnectionManager;
-use regex::Regex;
-use reqwest::{Client, StatusCode};
 use rusqlite::Connection;
+#[cfg(feature = "workers")]
+use rusqlite::{OptionalExtension, TransactionBehavior};
+#[cfg(feature = "workers")]
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "workers")]
 use serde_json::json;
 use snafu::{OptionExt, ResultExt};
+#[cfg(feature = "workers")]
 use soup::{NodeExt, QueryBuilderExt, Soup};
+#[cfg(feature = "workers")]
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+#[cfg(feature = "workers")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "workers")]
 use tokio::task::spawn_blocking;
-use tokio::time::{interval, sleep, MissedTickBehavior};
+#[cfg(feature = "workers")]
+use tokio::time::sleep;
 
+#[cfg(feature = "workers")]
 #[derive(Serialize, Deserialize)]
 pub struct CollectorsData {
     pub thumbs: Vec<Collector>,
@@ -26,12 +45,31 @@ pub struct CollectorsData {
     pub shown_thumbs: Vec<Collector>,
 }
 
+#[cfg(feature = "workers")]
 #[derive(Serialize, Deserialize)]
 pub struct AlbumProperties {
     pub item_type: String,
     pub item_id: i64,
 }
 
+#[cfg(feature = "server")]
+#[derive(Serialize, Deserialize)]
+pub struct TralbumCurrent {
+    pub title: String,
+    pub band_id: i64,
+}
+
+#[cfg(feature = "server")]
+#[derive(Serialize, Deserialize)]
+pub struct TralbumData {
+    pub id: i64,
+    pub item_type: ItemType,
+    pub artist: String,
+    pub url: String,
+    pub current: TralbumCurrent,
+}
+
+#[cfg(feature = "workers")]
 #[derive(Serialize, Deserialize)]
 pub struct CollectorsResult {
     pub results: Vec<Collector>,
@@ -39,10 +77,10 @@ pub struct CollectorsResult {
 }
 
 const SELECT_PRESENT_AND_RECENT_ITEM: &str = r#"
-select unixepoch('now') - unixepoch(last_updated, '30 days') from item where item_id = ?
+select item_is_stale(last_updated) from item where item_id = ?
 "#;
 
-fn item_present_and_recent(db: &Connection, item_id: i64) -> Result<bool, Error> {
+pub fn item_present_and_recent(db: &Connection, item_id: i64) -> Result<bool, Error> {
     let mut stmt = db
         .prepare_cached(SELECT_PRESENT_AND_RECENT_ITEM)
         .context(DbPrepareSnafu)?;
@@ -51,8 +89,8 @@ fn item_present_and_recent(db: &Connection, item_id: i64) -> Result<bool, Error>
         .context(DbReadSnafu)?
         .next()
         .context(DbReadSnafu)?
-        .and_then(|row| row.get::<usize, i64>(0).ok())
-        .map(|v| v < 0)
+        .and_then(|row| row.get::<usize, bool>(0).ok())
+        .map(|stale| !stale)
         .unwrap_or(false); // not present
     Ok(present)
 }
@@ -68,11 +106,103 @@ pub fn get_item(db: &Connection, track_id: i64) -> Result<Item, Error> {
     crate::types::item_from_row(res).context(DbReadSnafu)
 }
 
+const SELECT_ITEM_EXISTS: &str = r#"
+select exists(select 1 from item where item_id = ?)"#;
+
+pub fn item_exists(db: &Connection, item_id: i64) -> Result<bool, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_ITEM_EXISTS)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query_row([item_id], |row| row.get(0))
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+#[cfg(feature = "server")]
+const SELECT_ITEM_ID_FOR_URL: &str = r#"
+select item_id from item where item_url = ?"#;
+
+#[cfg(feature = "server")]
+pub fn get_item_id_for_url(db: &Connection, url: &str) -> Result<Option<i64>, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_ITEM_ID_FOR_URL)
+        .context(DbPrepareSnafu)?;
+    #[allow(clippy::let_and_return)]
+    let result = stmt
+        .query([url])
+        .context(DbReadSnafu)?
+        .next()
+        .context(DbReadSnafu)?
+        .map(|r| r.get(0))
+        .transpose()
+        .context(DbReadSnafu);
+    result
+}
+
+/// Strips query, fragment and trailing slashes, as stored in `item.item_url`
+#[cfg(feature = "server")]
+pub fn normalize_item_url(url: &str) -> &str {
+    let end = url.find(['?', '#']).unwrap_or(url.len());
+    url[..end].trim_end_matches('/')
+}
+
+/// Looks up an item by its url, fetching the album page if it is not yet known
+#[cfg(feature = "server")]
+pub async fn fetch_item_id_for_url(
+    db: &Pool<SqliteConnectionManager>,
+    client: &BandcampClient,
+    url: &str,
+) -> Result<i64, Error> {
+    let url = normalize_item_url(url);
+    if !client.is_item_url(url) {
+        return Err(Error::NotFoundError);
+    }
+    let conn = db.get().context(DbPoolSnafu)?;
+    if let Some(item_id) = get_item_id_for_url(&conn, url)? {
+        return Ok(item_id);
+    }
+    drop(conn);
+    println!("Reading item page {url}");
+    let body = client.get(url).await?;
+    let db = db.clone();
+    spawn_blocking(move || {
+        let soup = Soup::new(&body);
+        let node = soup.attr_name("data-tralbum").find().context(PageSnafu)?;
+        let attrs = node.attrs();
+        let body = attrs.get("data-tralbum").context(PageSnafu)?;
+        let tralbum: TralbumData = serde_json::from_str(body).context(SerializationSnafu)?;
+        let conn = db.get().context(DbPoolSnafu)?;
+        add_item(
+            &conn,
+            &Item {
+                item_id: tralbum.id,
+                item_type: tralbum.item_type,
+                item_title: tralbum.current.title,
+                item_url: normalize_item_url(&tralbum.url).to_string(),
+                album_id: None,
+                album_title: None,
+                band_id: tralbum.current.band_id,
+                band_name: tralbum.artist,
+                token: None,
+                also_collected_count: 0,
+                score: None,
+                explanation: None,
+                purchased: None,
+            },
+        )
+    })
+    .await
+    .unwrap()
+}
+
+#[cfg(feature = "workers")]
 const INSERT_COLLECTED_BY: &str = r#"
 insert or ignore into collected_by (item_id, fan_id)
 values (?, ?)
 returning 1"#;
 
+#[cfg(feature = "workers")]
 fn add_collector_for_item(
     db: &Connection,
     item_id: i64,
@@ -92,38 +222,25 @@ fn add_collector_for_item(
     Ok(res)
 }
 
+#[cfg(feature = "workers")]
 struct PageResults {
     token: String,
     album_id: i64,
     album_type: String,
 }
 
-lazy_static! {
-    static ref BANDCAMP_REGEX: Regex = Regex::new("^https?://[a-z0-9-]+\\.bandcamp\\.com").unwrap();
-}
-
+#[cfg(feature = "workers")]
 async fn get_initial_page(
     db: &Pool<SqliteConnectionManager>,
+    client: &BandcampClient,
     item: &Item,
 ) -> Result<Option<PageResults>, Error> {
     println!("Fetching collectors for {}", item.item_title);
     // Not a bandcamp url
-    if !BANDCAMP_REGEX.is_match(&item.item_url) {
-        return Err(Error::NotFoundError);
-    }
-    let client = Client::new();
-    let page = client
-        .get(&item.item_url)
-        .send()
-        .await
-        .context(NetworkSnafu)?;
-    if page.status() == StatusCode::TOO_MANY_REQUESTS {
-        return Err(Error::RateLimit);
-    }
-    if page.status() == StatusCode::NOT_FOUND {
+    if !client.is_item_url(&item.item_url) {
         return Err(Error::NotFoundError);
     }
-    let body = page.text().await.context(NetworkSnafu)?;
+    let body = client.get(&item.item_url).await?;
     let item_id = item.item_id;
     let db = db.clone();
     let result = spawn_blocking(move || {
@@ -148,13 +265,17 @@ async fn get_initial_page(
         let collectors: CollectorsData = serde_json::from_str(body).context(SerializationSnafu)?;
         let mut token = "".to_string();
         let mut done = false;
-        let conn = db.get().context(DbPoolSnafu)?;
+        let mut conn = db.get().context(DbPoolSnafu)?;
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context(DbWriteSnafu)?;
         for collector in collectors.thumbs {
-            done = add_collector_for_item(&conn, item_id, &collector)? || done;
+            done = add_collector_for_item(&tx, item_id, &collector)? || done;
             if let Some(current_token) = collector.token {
                 token = current_token;
             }
         }
+        tx.commit().context(DbWriteSnafu)?;
         if !done && collectors.more_thumbs_available {
             let node = soup
                 .attr("name", "bc-page-properties")
@@ -180,31 +301,25 @@ async fn get_initial_page(
     Ok(result)
 }
 
+#[cfg(feature = "workers")]
 async fn get_next_page(
     db: &Pool<SqliteConnectionManager>,
+    client: &BandcampClient,
     item: &Item,
     page_result: &PageResults,
 ) -> Result<Option<String>, Error> {
     println!("Fetching more collectors for {}", item.item_title);
-    let client = Client::new();
-    let result = client
-        .post("https://bandcamp.com/api/tralbumcollectors/2/thumbs")
-        .body(
+    let body = client
+        .post(
+            "/api/tralbumcollectors/2/thumbs",
             json!({
                 "count": 500,
                 "token": page_result.token,
                 "tralbum_id": page_result.album_id,
                 "tralbum_type": page_result.album_type,
-            })
-            .to_string(),
+            }),
         )
-        .send()
-        .await
-        .context(NetworkSnafu)?;
-    if result.status() == StatusCode::TOO_MANY_REQUESTS {
-        return Err(Error::RateLimit);
-    }
-    let body = result.text().await.context(NetworkSnafu)?;
+        .await?;
     let item_id = item.item_id;
     let db = db.clone();
     let mut token = page_result.token.clone();
@@ -212,13 +327,17 @@ async fn get_next_page(
         let collectors: CollectorsResult =
             serde_json::from_str(&body).context(SerializationSnafu)?;
         let mut done = false;
-        let conn = db.get().context(DbPoolSnafu)?;
+        let mut conn = db.get().context(DbPoolSnafu)?;
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context(DbWriteSnafu)?;
         for collector in collectors.results {
-            done = add_collector_for_item(&conn, item_id, &collector)? || done;
+            done = add_collector_for_item(&tx, item_id, &collector)? || done;
             if let Some(current_token) = collector.token {
                 token = current_token;
             }
         }
+        tx.commit().context(DbWriteSnafu)?;
         if !done && collectors.more_available {
             Ok(Some(token))
         } else {
@@ -229,8 +348,10 @@ async fn get_next_page(
     .unwrap()
 }
 
+#[cfg(feature = "workers")]
 pub async fn fetch_track_collectors(
     db: &Pool<SqliteConnectionManager>,
+    client: &BandcampClient,
     item_id: i64,
 ) -> Result<(), Error> {
     let conn = db.get().context(DbPoolSnafu)?;
@@ -239,77 +360,60 @@ pub async fn fetch_track_collectors(
     }
     let item = get_item(&conn, item_id)?;
     drop(conn);
-    let result = get_initial_page(db, &item).await?;
+    let result = get_initial_page(db, client, &item).await?;
     if let Some(mut result) = result {
-        while let Some(token) = get_next_page(db, &item, &result).await? {
+        while let Some(token) = get_next_page(db, client, &item, &result).await? {
             result.token = token;
         }
     }
     Ok(())
 }
 
-const SELECT_FIRST_QUEUE_ITEM: &str = r#"
-select item_id from item_collected_by_queue
-order by item_id asc
-limit 1"#;
-
+// items with a job are either queued already or waiting for a retry
+#[cfg(feature = "workers")]
 const SELECT_UNFINISHED: &str = r#"
 select item_id from item
-where unixepoch('now') > unixepoch(last_updated, '30 days')
+where item_is_stale(last_updated)
+and item_id not in (select target_id from job where kind = 'item')
 order by item_id asc
 limit 1"#;
 
+#[cfg(feature = "workers")]
 fn get_next_item(db: &Connection, crawl: bool) -> Result<Option<i64>, Error> {
-    let mut stmt = db
-        .prepare_cached(SELECT_FIRST_QUEUE_ITEM)
-        .context(DbPrepareSnafu)?;
-    let mut rows = stmt.query([]).context(DbReadSnafu)?;
-    let row = rows.next().context(DbReadSnafu)?;
-    if let Some(row) = row {
-        let item_id: i64 = row.get("item_id").context(DbReadSnafu)?;
+    if let Some(item_id) = jobs::next_job(db, JobKind::Item)? {
         Ok(Some(item_id))
-    } else if crawl {
+    } else if crawl && !jobs::crawl_paused(db)? {
         let mut stmt = db
             .prepare_cached(SELECT_UNFINISHED)
             .context(DbPrepareSnafu)?;
-        let mut rows = stmt.query([]).context(DbReadSnafu)?;
-        let row = rows.next().context(DbReadSnafu)?;
-        if let Some(row) = row {
-            let item_id: i64 = row.get("item_id").context(DbReadSnafu)?;
-            Ok(Some(item_id))
-        } else {
-            Ok(None)
-        }
+        let result = stmt
+            .query_row([], |row| row.get(0))
+            .optional()
+            .context(DbReadSnafu)?;
+        Ok(result)
     } else {
         Ok(None)
     }
 }
 
+#[cfg(feature = "workers")]
 const MARK_ITEM_DONE: &str = r#"
 update item
 set last_updated = unixepoch('now')
 where item_id = ?"#;
 
+#[cfg(feature = "workers")]
 fn mark_item_done(db: &Connection, item_id: i64) -> Result<(), Error> {
     let mut stmt = db.prepare_cached(MARK_ITEM_DONE).context(DbPrepareSnafu)?;
     stmt.execute([item_id]).context(DbWriteSnafu)?;
     Ok(())
 }
 
-const DELETE_QUEUE_ITEM: &str = r#"
-delete from item_collected_by_queue where item_id = ?"#;
-
-fn remove_from_queue(db: &Connection, item_id: i64) -> Result<(), Error> {
-    let mut stmt = db
-        .prepare_cached(DELETE_QUEUE_ITEM)
-        .context(DbPrepareSnafu)?;
-    stmt.execute([item_id]).context(DbWriteSnafu)?;
-    Ok(())
-}
-
+#[cfg(feature = "workers")]
 const DELETE_COLLECTED_BY: &str = r#"
 delete from collected_by where item_id = ?"#;
 
+#[cfg(feature = "workers")]
 fn remove_collected_by(db: &Connection, item_id: i64) -> Result<(), Error> {
     let mut stmt = db
         .prepare_cached(DELETE_COLLECTED_BY)
@@ -318,41 +422,83 @@ fn remove_collected_by(db: &Connection, item_id: i64) -> Result<(), Error> {
     Ok(())
 }
 
+// Polling interval while there is nothing to fetch
+#[cfg(feature = "workers")]
+const IDLE_INTERVAL: Duration = Duration::from_secs(3);
+
+#[cfg(feature = "workers")]
 pub async fn item_worker(
     db: &Pool<SqliteConnectionManager>,
+    client: &BandcampClient,
     crawl: bool,
     run_state: &AtomicBool,
 ) -> Result<(), Error> {
-    let mut timer = interval(Duration::from_secs(3));
-    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
     while run_state.load(Ordering::Relaxed) {
         let conn = db.get().context(DbPoolSnafu)?;
         if let Some(item_id) = get_next_item(&conn, crawl)? {
+            if item_present_and_recent(&conn, item_id)? {
+                // refreshed since it was queued, this would skew the measured durations
+                jobs::complete(&conn, JobKind::Item, item_id)?;
+                continue;
+            }
             drop(conn);
-            match fetch_track_collectors(db, item_id).await {
+            let started = Instant::now();
+            match fetch_track_collectors(db, client, item_id).await {
                 Err(Error::RateLimit) => {
-                    println!("Rate limited, waiting 10 seconds");
+                    // the shared limiter is already backing off, retry later
+                    println!("Rate limited, retrying item {item_id} later");
                     let conn = db.get().context(DbPoolSnafu)?;
                     remove_collected_by(&conn, item_id)?;
-                    sleep(Duration::from_secs(10)).await
                 }
                 Err(Error::NotFoundError) => {
                     println!("Item with id {item_id} not found");
                     let conn = db.get().context(DbPoolSnafu)?;
                     mark_item_done(&conn, item_id)?;
-                    remove_from_queue(&conn, item_id)?;
+                    jobs::complete(&conn, JobKind::Item, item_id)?;
+                    jobs::record_duration(&conn, JobKind::Item, started.elapsed())?;
                 }
                 Err(err) => {
                     println!("Error while processing item {item_id}: {err}");
+                    let conn = db.get().context(DbPoolSnafu)?;
+                    jobs::record_failure(&conn, JobKind::Item, item_id, &err)?;
                 }
                 Ok(()) => {
                     let conn = db.get().context(DbPoolSnafu)?;
                     mark_item_done(&conn, item_id)?;
-                    remove_from_queue(&conn, item_id)?;
+                    jobs::complete(&conn, JobKind::Item, item_id)?;
+                    jobs::record_duration(&conn, JobKind::Item, started.elapsed())?;
                 }
             }
+        } else {
+            sleep(IDLE_INTERVAL).await;
         }
-        timer.tick().await;
     }
     Ok(())
 }
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::mock_server::MockServer;
+    use crate::store::test_pool;
+
+    #[actix_web::test]
+    async fn resolves_and_fetches_all_collector_pages() {
+        let server = MockServer::start();
+        let client = server.client();
+        let db = test_pool("track_collectors");
+        let url = format!("{}/album/first/?from=search", server.base_url);
+        let item_id = fetch_item_id_for_url(&db, &client, &url).await.unwrap();
+        assert_eq!(item_id, 101);
+        fetch_track_collectors(&db, &client, item_id).await.unwrap();
+        let conn = db.get().unwrap();
+        let collectors: Vec<i64> = conn
+            .prepare("select fan_id from collected_by where item_id = 101 order by fan_id")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(collectors, vec![2, 3]);
+    }
+}
@@ -0,0 +1,381 @@
+use crate::types::{job_from_row, Job, JobKind};
+use crate::{DbPrepareSnafu, DbReadSnafu, DbWriteSnafu, Error};
+use fallible_iterator::FallibleIterator;
+use rusqlite::{Connection, OptionalExtension};
+use snafu::ResultExt;
+#[cfg(feature = "workers")]
+use std::time::Duration;
+
+// Jobs are retried after 1, 2, 4 and 8 minutes, then marked as failed
+#[cfg(feature = "workers")]
+const MAX_ATTEMPTS: i64 = 5;
+#[cfg(feature = "workers")]
+const RETRY_DELAY: i64 = 60; // seconds
+
+// Crawled work only gets a job once it failed, otherwise it runs when no jobs are left
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    #[cfg(feature = "workers")]
+    Crawl = 0,
+    Neighbour = 1,
+    Interactive = 2,
+}
+
+const INSERT_JOB: &str = r#"
+insert into job (kind, target_id, priority, requested_by) values (?, ?, ?, ?)
+on conflict do update
+set priority = max(priority, excluded.priority),
+    requested_by = coalesce(requested_by, excluded.requested_by)"#;
+
+pub fn enqueue(
+    db: &Connection,
+    kind: JobKind,
+    target_id: i64,
+    priority: Priority,
+    requested_by: Option<i64>,
+) -> Result<(), Error> {
+    let mut stmt = db.prepare_cached(INSERT_JOB).context(DbPrepareSnafu)?;
+    stmt.execute((kind, target_id, priority as i64, requested_by))
+        .context(DbWriteSnafu)?;
+    Ok(())
+}
+
+// Within a priority, the target that waited longest for its turn goes first
+#[cfg(feature = "workers")]
+const SELECT_NEXT_JOB: &str = r#"
+select target_id, requested_by from job
+left join target_turn on fan_id = requested_by
+where kind = ? and state = 'queued' and next_attempt <= unixepoch('now') and priority >= ?
+order by priority desc, coalesce(turn, 0) asc, target_id asc
+limit 1"#;
+
+#[cfg(feature = "workers")]
+const SELECT_TARGET_JOBS_DUE: &str = r#"
+select exists(
+    select 1 from job
+    where state = 'queued' and next_attempt <= unixepoch('now') and priority > ?
+)"#;
+
+// The workers of all kinds share one rate limit, so crawling in one of them would slow down
+// the jobs of targets in the other. Crawled work waits until no target has jobs left
+#[cfg(feature = "workers")]
+pub fn crawl_paused(db: &Connection) -> Result<bool, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_TARGET_JOBS_DUE)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query_row([Priority::Crawl as i64], |row| row.get(0))
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+#[cfg(feature = "workers")]
+const UPDATE_TARGET_TURN: &str = r#"
+insert into target_turn (fan_id, turn)
+values (?1, (select coalesce(max(turn), 0) + 1 from target_turn))
+on conflict do update
+set turn = excluded.turn"#;
+
+#[cfg(feature = "workers")]
+pub fn next_job(db: &Connection, kind: JobKind) -> Result<Option<i64>, Error> {
+    let min_priority = if crawl_paused(db)? {
+        Priority::Neighbour
+    } else {
+        Priority::Crawl
+    };
+    let mut stmt = db.prepare_cached(SELECT_NEXT_JOB).context(DbPrepareSnafu)?;
+    let result: Option<(i64, Option<i64>)> = stmt
+        .query_row((kind, min_priority as i64), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .optional()
+        .context(DbReadSnafu)?;
+    let Some((target_id, requested_by)) = result else {
+        return Ok(None);
+    };
+    if let Some(requested_by) = requested_by {
+        let mut stmt = db
+            .prepare_cached(UPDATE_TARGET_TURN)
+            .context(DbPrepareSnafu)?;
+        stmt.execute([requested_by]).context(DbWriteSnafu)?;
+    }
+    Ok(Some(target_id))
+}
+
+const DELETE_TARGET_TURN: &str = r#"
+delete from target_turn where fan_id = ?"#;
+
+pub fn remove_target_turn(db: &Connection, fan_id: i64) -> Result<(), Error> {
+    let mut stmt = db
+        .prepare_cached(DELETE_TARGET_TURN)
+        .context(DbPrepareSnafu)?;
+    stmt.execute([fan_id]).context(DbWriteSnafu)?;
+    Ok(())
+}
+
+#[cfg(feature = "workers")]
+const DELETE_JOB: &str = r#"
+delete from job where kind = ? and target_id = ?"#;
+
+#[cfg(feature = "workers")]
+pub fn complete(db: &Connection, kind: JobKind, target_id: i64) -> Result<(), Error> {
+    let mut stmt = db.prepare_cached(DELETE_JOB).context(DbPrepareSnafu)?;
+    stmt.execute((kind, target_id)).context(DbWriteSnafu)?;
+    Ok(())
+}
+
+// Crawled work has no job yet, so failures create one to keep it from being picked again right away
+#[cfg(feature = "workers")]
+const UPSERT_FAILED_JOB: &str = r#"
+insert into job (kind, target_id, attempts, last_error, next_attempt, priority)
+values (?1, ?2, 1, ?3, unixepoch('now') + ?4, ?6)
+on conflict do update
+set attempts = attempts + 1,
+    last_error = excluded.last_error,
+    next_attempt = unixepoch('now') + ?4 * (1 << attempts),
+    state = case when attempts + 1 >= ?5 then 'failed' else 'queued' end
+returning state"#;
+
+#[cfg(feature = "workers")]
+pub fn record_failure(
+    db: &Connection,
+    kind: JobKind,
+    target_id: i64,
+    error: &Error,
+) -> Result<(), Error> {
+    let mut stmt = db
+        .prepare_cached(UPSERT_FAILED_JOB)
+        .context(DbPrepareSnafu)?;
+    let state: String = stmt
+        .query_row(
+            (
+                kind,
+                target_id,
+                error.to_string(),
+                RETRY_DELAY,
+                MAX_ATTEMPTS,
+                Priority::Crawl as i64,
+            ),
+            |row| row.get(0),
+        )
+        .context(DbWriteSnafu)?;
+    if state == "failed" {
+        println!("Giving up on {kind:?} {target_id} after {MAX_ATTEMPTS} attempts");
+    }
+    Ok(())
+}
+
+// Weight of the newest duration in the moving average
+#[cfg(feature = "workers")]
+const DURATION_SMOOTHING: f64 = 0.05;
+
+#[cfg(feature = "workers")]
+const UPSERT_JOB_DURATION: &str = r#"
+insert into job_stats (kind, mean, variance) values (?1, ?2, 0)
+on conflict do update
+set mean = mean + ?3 * (excluded.mean - mean),
+    variance = (1 - ?3) * (variance + ?3 * (excluded.mean - mean) * (excluded.mean - mean))"#;
+
+#[cfg(feature = "workers")]
+pub fn record_duration(db: &Connection, kind: JobKind, duration: Duration) -> Result<(), Error> {
+    let mut stmt = db
+        .prepare_cached(UPSERT_JOB_DURATION)
+        .context(DbPrepareSnafu)?;
+    stmt.execute((kind, duration.as_secs_f64(), DURATION_SMOOTHING))
+        .context(DbWriteSnafu)?;
+    Ok(())
+}
+
+const SELECT_JOB_DURATION: &str = r#"
+select mean, variance from job_stats where kind = ?"#;
+
+// Mean and variance of the job duration in seconds, if any job was measured yet
+pub fn get_duration_stats(db: &Connection, kind: JobKind) -> Result<Option<(f64, f64)>, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_JOB_DURATION)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query_row([kind], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+// Other targets take turns with this one, so each of them is ahead with at most `count` jobs
+const SELECT_JOBS_AHEAD: &str = r#"
+select (
+    select count(*) from job
+    where kind = ?1 and state = 'queued' and priority > ?2
+) + coalesce((
+    select sum(min(n, ?4)) from (
+        select count(*) as n from job
+        where kind = ?1 and state = 'queued' and priority = ?2 and requested_by is not ?3
+        group by requested_by
+    )
+), 0)"#;
+
+pub fn count_jobs_ahead(
+    db: &Connection,
+    kind: JobKind,
+    priority: Priority,
+    requested_by: Option<i64>,
+    count: i64,
+) -> Result<i64, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_JOBS_AHEAD)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query_row((kind, priority as i64, requested_by, count), |row| {
+            row.get(0)
+        })
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+const SELECT_JOBS: &str = r#"
+select * from job
+where ?1 is null or state = ?1
+order by kind, priority desc, target_id
+limit ?2"#;
+
+pub fn get_jobs(db: &Connection, state: Option<&str>, limit: usize) -> Result<Vec<Job>, Error> {
+    let mut stmt = db.prepare_cached(SELECT_JOBS).context(DbPrepareSnafu)?;
+    let result = stmt
+        .query((state, limit))
+        .context(DbReadSnafu)?
+        .map(job_from_row)
+        .collect()
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+const REQUEUE_FAILED_JOBS: &str = r#"
+update job
+set state = 'queued', attempts = 0, next_attempt = unixepoch('now')
+where state = 'failed' and (?1 is null or kind = ?1) and (?2 is null or target_id = ?2)"#;
+
+pub fn requeue_failed(
+    db: &Connection,
+    kind: Option<JobKind>,
+    target_id: Option<i64>,
+) -> Result<usize, Error> {
+    let mut stmt = db
+        .prepare_cached(REQUEUE_FAILED_JOBS)
+        .context(DbPrepareSnafu)?;
+    let result = stmt.execute((kind, target_id)).context(DbWriteSnafu)?;
+    Ok(result)
+}
+
+#[cfg(all(test, feature = "workers"))]
+mod tests {
+    use super::*;
+    use crate::store::test_pool;
+
+    #[test]
+    fn fails_after_max_attempts_and_requeues() {
+        let db = test_pool("jobs");
+        let conn = db.get().unwrap();
+        enqueue(&conn, JobKind::Item, 1, Priority::Interactive, None).unwrap();
+        assert_eq!(next_job(&conn, JobKind::Item).unwrap(), Some(1));
+        assert_eq!(next_job(&conn, JobKind::Collector).unwrap(), None);
+        for _ in 0..MAX_ATTEMPTS {
+            record_failure(&conn, JobKind::Item, 1, &Error::PageError).unwrap();
+            // waiting for a retry
+            assert_eq!(next_job(&conn, JobKind::Item).unwrap(), None);
+        }
+        let failed = get_jobs(&conn, Some("failed"), 10).unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].attempts, MAX_ATTEMPTS);
+        assert_eq!(failed[0].last_error.as_deref(), Some("Page content error"));
+        assert_eq!(requeue_failed(&conn, Some(JobKind::Item), None).unwrap(), 1);
+        assert_eq!(next_job(&conn, JobKind::Item).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn prefers_interactive_jobs_and_alternates_targets() {
+        let db = test_pool("job_priorities");
+        let conn = db.get().unwrap();
+        enqueue(&conn, JobKind::Item, 1, Priority::Crawl, None).unwrap();
+        for item_id in [10, 11] {
+            enqueue(
+                &conn,
+                JobKind::Item,
+                item_id,
+                Priority::Interactive,
+                Some(100),
+            )
+            .unwrap();
+        }
+        for item_id in [20, 21] {
+            enqueue(
+                &conn,
+                JobKind::Item,
+                item_id,
+                Priority::Interactive,
+                Some(200),
+            )
+            .unwrap();
+        }
+        let mut order = Vec::new();
+        while let Some(item_id) = next_job(&conn, JobKind::Item).unwrap() {
+            complete(&conn, JobKind::Item, item_id).unwrap();
+            order.push(item_id);
+        }
+        assert_eq!(order, vec![10, 20, 11, 21, 1]);
+    }
+
+    #[test]
+    fn pauses_crawling_while_targets_have_jobs() {
+        let db = test_pool("job_crawl_pause");
+        let conn = db.get().unwrap();
+        enqueue(&conn, JobKind::Item, 1, Priority::Crawl, None).unwrap();
+        enqueue(&conn, JobKind::Collector, 2, Priority::Neighbour, Some(100)).unwrap();
+        assert!(crawl_paused(&conn).unwrap());
+        // the item worker waits for the collection worker
+        assert_eq!(next_job(&conn, JobKind::Item).unwrap(), None);
+        assert_eq!(next_job(&conn, JobKind::Collector).unwrap(), Some(2));
+        complete(&conn, JobKind::Collector, 2).unwrap();
+        assert!(!crawl_paused(&conn).unwrap());
+        assert_eq!(next_job(&conn, JobKind::Item).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn estimates_queue_position_and_durations() {
+        let db = test_pool("job_estimates");
+        let conn = db.get().unwrap();
+        assert_eq!(get_duration_stats(&conn, JobKind::Item).unwrap(), None);
+        record_duration(&conn, JobKind::Item, Duration::from_secs(4)).unwrap();
+        record_duration(&conn, JobKind::Item, Duration::from_secs(4)).unwrap();
+        assert_eq!(
+            get_duration_stats(&conn, JobKind::Item).unwrap(),
+            Some((4.0, 0.0))
+        );
+        enqueue(&conn, JobKind::Item, 1, Priority::Interactive, None).unwrap();
+        for item_id in 10..15 {
+            enqueue(
+                &conn,
+                JobKind::Item,
+                item_id,
+                Priority::Interactive,
+                Some(100),
+            )
+            .unwrap();
+        }
+        for item_id in 20..22 {
+            enqueue(
+                &conn,
+                JobKind::Item,
+                item_id,
+                Priority::Interactive,
+                Some(200),
+            )
+            .unwrap();
+        }
+        enqueue(&conn, JobKind::Item, 30, Priority::Crawl, None).unwrap();
+        // the unowned job and both jobs of target 200 go before the last of 3 jobs of target 100
+        let ahead = count_jobs_ahead(&conn, JobKind::Item, Priority::Interactive, Some(100), 3);
+        assert_eq!(ahead.unwrap(), 3);
+        let ahead = count_jobs_ahead(&conn, JobKind::Item, Priority::Crawl, None, 1);
+        assert_eq!(ahead.unwrap(), 8);
+    }
+}
@@ -0,0 +1,166 @@
+//! Scrapes bandcamp collections and recommends items based on what similar collectors bought.
+//!
+//! Without default features only the analysis of an existing database is available, the
+//! `workers` feature adds the scraper and `server` the web interface. `export` and `evaluate`
+//! add exports and imports of tables and the offline evaluation.
+
+mod analyze;
+#[cfg(feature = "workers")]
+mod client;
+mod collectors;
+mod cooccurrence;
+mod dismissals;
+#[cfg(feature = "evaluate")]
+mod evaluate;
+#[cfg(feature = "export")]
+mod export;
+mod feed;
+mod freshness;
+#[cfg(feature = "export")]
+mod import;
+mod items;
+mod jobs;
+mod migrations;
+#[cfg(all(test, feature = "server"))]
+mod mock_server;
+mod progress_manager;
+#[cfg(feature = "workers")]
+mod rate_limiter;
+mod recommenders;
+#[cfg(feature = "server")]
+mod server;
+mod stats;
+mod store;
+mod types;
+#[cfg(feature = "workers")]
+mod workers;
+
+use snafu::Snafu;
+
+pub use analyze::{
+    Aggregation, RankingCache, RankingParams, RecommendationFilter, WishlistMode,
+    get_group_recommendations, get_similar_items, get_user_feed, get_user_recommendations,
+};
+pub use collectors::{get_fan_id_for_username, get_purchases};
+pub use dismissals::{Dismissal, dismiss, undismiss};
+pub use feed::render_atom;
+pub use freshness::{DAY, FreshnessPolicy, invalidate_for_user};
+pub use jobs::{get_jobs, requeue_failed};
+pub use migrations::{LATEST_VERSION, Migration};
+pub use progress_manager::{add_item_target, add_target, get_target};
+pub use recommenders::{Recommender, Strategy};
+pub use stats::{Stats, get_stats};
+pub use store::Store;
+pub use types::{
+    Collector, Explanation, FeedEntry, Item, ItemTarget, ItemType, Job, JobKind, Purchase,
+    RecommendationPage, Target,
+};
+
+#[cfg(feature = "evaluate")]
+pub use evaluate::{EvaluationConfig, EvaluationReport, evaluate};
+#[cfg(feature = "export")]
+pub use export::{ExportFormat, ExportOptions, ExportTable, export_table};
+#[cfg(feature = "export")]
+pub use import::import_table;
+
+#[cfg(feature = "workers")]
+pub use client::{BandcampClient as Client, DEFAULT_BASE_URL};
+#[cfg(feature = "workers")]
+pub use collectors::fetch_collection;
+#[cfg(feature = "workers")]
+pub use items::fetch_track_collectors;
+#[cfg(feature = "workers")]
+pub use rate_limiter::{DEFAULT_BURST, DEFAULT_REQUESTS_PER_SECOND, RateLimiter};
+#[cfg(feature = "workers")]
+pub use workers::spawn_workers;
+
+#[cfg(feature = "server")]
+pub use server::{AdminConfig, server};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[cfg(feature = "workers")]
+    #[snafu(display("Network error: {:?}", source))]
+    NetworkError { source: reqwest::Error },
+
+    #[snafu(display("Resource not found"))]
+    NotFoundError,
+
+    #[snafu(display("Serialization error: {:?}", source))]
+    SerializationError { source: serde_json::Error },
+
+    #[snafu(display("Error opening database: {:?}", source))]
+    DbOpenError { source: rusqlite::Error },
+
+    #[snafu(display("Error preparing database statement: {:?}", source))]
+    DbPrepareError { source: rusqlite::Error },
+
+    #[snafu(display("Database read error: {:?}", source))]
+    DbReadError { source: rusqlite::Error },
+
+    #[snafu(display("Database write error: {:?}", source))]
+    DbWriteError { source: rusqlite::Error },
+
+    #[snafu(display("Error opening database: {:?}", source))]
+    DbPoolError { source: r2d2::Error },
+
+    #[snafu(display("No row returned from database"))]
+    DbResultError,
+
+    #[snafu(display("Rate limit reached"))]
+    RateLimit,
+
+    #[cfg(feature = "workers")]
+    #[snafu(display("Invalid request rate {rate}, it has to be greater than 0"))]
+    InvalidRateError { rate: f64 },
+
+    #[snafu(display("Page content error"))]
+    PageError,
+
+    #[snafu(display("IO error: {:?}", source))]
+    IoError { source: std::io::Error },
+
+    #[snafu(display(
+        "Database schema version {version} is newer than the latest known version {latest}"
+    ))]
+    SchemaTooNewError { version: i64, latest: i64 },
+
+    #[snafu(display(
+        "Database schema version {version} is older than {latest}, run the migrate command"
+    ))]
+    SchemaOutdatedError { version: i64, latest: i64 },
+
+    #[cfg(feature = "export")]
+    #[snafu(display("CSV error: {:?}", source))]
+    CsvError { source: csv::Error },
+
+    #[cfg(feature = "parquet")]
+    #[snafu(display("Parquet error: {:?}", source))]
+    ParquetError {
+        source: parquet::errors::ParquetError,
+    },
+
+    #[cfg(feature = "parquet")]
+    #[snafu(display("Arrow error: {:?}", source))]
+    ArrowError { source: arrow_schema::ArrowError },
+
+    #[cfg(feature = "export")]
+    #[snafu(display("Format {format} is not supported by this build"))]
+    UnsupportedFormatError { format: String },
+
+    #[cfg(feature = "export")]
+    #[snafu(display("Unknown column {column}"))]
+    UnknownColumnError { column: String },
+
+    #[cfg(feature = "export")]
+    #[snafu(display("Invalid value {value} for column {column}"))]
+    InvalidValueError { column: String, value: String },
+
+    #[cfg(feature = "evaluate")]
+    #[snafu(display("Invalid evaluation: {reason}"))]
+    InvalidEvaluationError { reason: String },
+
+    #[cfg(feature = "export")]
+    #[snafu(display("Anonymized exports need a salt"))]
+    MissingSaltError,
+}
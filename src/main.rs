@@ -1,210 +1,106 @@
 use std::sync::atomic::{AtomicBool, Ordering};
 
-use actix_web::http::header::ContentType;
-use actix_web::{App, HttpResponse, HttpServer, get, web};
+use bandcamp_recommendations::{
+    AdminConfig, Client, Error, RateLimiter, Store, evaluate, server, spawn_workers,
+};
 use clap::Parser;
-use r2d2::Pool;
-use r2d2_sqlite::SqliteConnectionManager;
-use serde::Deserialize;
-use snafu::Snafu;
-use tokio::task::spawn_blocking;
-use tokio::{join, spawn};
+use futures_util::future;
+use tokio::join;
+use tokio::sync::broadcast;
 
-mod analyze;
 mod args;
-mod collectors;
-mod items;
-mod progress_manager;
-mod types;
-
-type DataType = web::Data<Pool<SqliteConnectionManager>>;
-
-#[derive(Deserialize)]
-struct UserInfo {
-    username: String,
-}
+mod commands;
 
 static RUN_STATE: AtomicBool = AtomicBool::new(true);
 
-#[get("/api/get_status")]
-async fn get_status(query: web::Query<UserInfo>, data: DataType) -> HttpResponse {
-    let conn = data.get().unwrap();
-    let fan_id = match collectors::get_fan_id_for_username(&conn, &query.username) {
-        Ok(Some(fan_id)) => fan_id,
-        Ok(Option::None) => {
-            return HttpResponse::NotFound().body("User not found");
-        }
-        Err(err) => {
-            println!("Error getting status for user: {err}");
-            return HttpResponse::InternalServerError().body("Internal server error");
-        }
-    };
-    match progress_manager::add_target(&conn, fan_id) {
-        Ok(target) => HttpResponse::Ok().body(serde_json::to_string(&target).unwrap()),
-        Err(err) => {
-            println!("Error getting status for user: {err}");
-            HttpResponse::InternalServerError().body("Internal server error")
-        }
-    }
-}
-
-#[get("/api/get_user")]
-async fn get_user(query: web::Query<UserInfo>, data: DataType) -> HttpResponse {
-    let result = collectors::fetch_collection(data.get_ref(), &query.username, true)
-        .await
-        .and_then(|_| collectors::get_collection_size(data.get_ref(), &query.username));
-    match result {
-        Ok(size) => {
-            if size > 2 {
-                HttpResponse::Ok().body("User fetched successfully")
-            } else {
-                HttpResponse::NotFound()
-                    .body("User does not contain enough items (at least 2 required)")
-            }
-        }
-        Err(Error::NotFoundError) => HttpResponse::NotFound().body("User not found"),
-        Err(err) => {
-            println!("Error fetching user: {err}");
-            HttpResponse::InternalServerError().body("Internal server error")
-        }
-    }
-}
-
-#[derive(Deserialize)]
-struct RecommendationInfo {
-    username: String,
-    similar_boost: Option<f64>,
-}
-
-#[get("/api/get_recommendations")]
-async fn get_recommendations(
-    query: web::Query<RecommendationInfo>,
-    data: DataType,
-) -> HttpResponse {
-    let similar_boost = query.similar_boost.unwrap_or(2.0).clamp(1.0, 5.0);
-    let result = spawn_blocking(move || {
-        analyze::get_user_recommendations(data.get_ref(), &query.username, similar_boost)
-    })
-    .await
-    .unwrap();
-    match result {
-        Ok(data) => HttpResponse::Ok().body(serde_json::to_string(&data).unwrap()),
-        Err(Error::NotFoundError) => HttpResponse::NotFound().body("User not found"),
-        Err(err) => {
-            println!("Error getting recommendations for user: {err}");
-            HttpResponse::InternalServerError().body("Internal server error")
-        }
+fn open_store(args: &args::Args, read_only: bool) -> Result<Store, Error> {
+    let policy = args.freshness.policy();
+    if read_only {
+        Store::open_read_only(&args.database, policy)
+    } else if matches!(args.command, args::Command::Migrate(_)) {
+        Store::open_unmigrated(&args.database, policy)
+    } else {
+        Store::open(&args.database, policy)
     }
 }
 
-#[get("/classless.css")]
-async fn get_classless() -> HttpResponse {
-    HttpResponse::Ok()
-        .content_type(ContentType(mime::TEXT_CSS))
-        .body(include_str!("../web_src/classless.css"))
+fn create_client(args: &args::ClientArgs) -> std::io::Result<Client> {
+    let limiter =
+        RateLimiter::new(args.requests_per_second, args.burst).map_err(std::io::Error::other)?;
+    Ok(Client::new(&args.base_url, limiter))
 }
 
-#[get("/index.html")]
-async fn get_index() -> HttpResponse {
-    HttpResponse::Ok()
-        .content_type(ContentType::html())
-        .body(include_str!("../web_src/index.html"))
-}
-
-#[get("/")]
-async fn get_root() -> HttpResponse {
-    HttpResponse::Ok()
-        .content_type(ContentType::html())
-        .body(include_str!("../web_src/index.html"))
-}
-
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let args = args::Args::parse();
-    let manager = SqliteConnectionManager::file(&args.database);
-    let pool = Pool::new(manager).expect("Unable to create sqlite pool");
-    pool.get()
-        .unwrap()
-        .execute_batch(include_str!("init.sql"))
-        .expect("Unable to initialize database");
-    let db_copy = pool.clone();
-    let collection_worker = spawn(async move {
-        while let Err(res) = collectors::collection_worker(&db_copy, args.crawl, &RUN_STATE).await {
-            println!("Error in collection_worker: {res}");
-        }
-    });
-    let db_copy = pool.clone();
-    let item_worker = spawn(async move {
-        while let Err(res) = items::item_worker(&db_copy, args.crawl, &RUN_STATE).await {
-            println!("Error in item_worker: {res}");
-        }
-    });
-    let db_copy = pool.clone();
-    let progress_manager = spawn(async move {
-        while let Err(res) = progress_manager::progress_manager(&db_copy, &RUN_STATE).await {
-            println!("Error in progress_manager: {res}");
-        }
-    });
-    let data = web::Data::new(pool.clone());
-    let server = HttpServer::new(move || {
-        App::new()
-            .app_data(data.clone())
-            .service(get_status)
-            .service(get_user)
-            .service(get_recommendations)
-            .service(get_classless)
-            .service(get_index)
-            .service(get_root)
-    })
-    .bind(args.address)?
-    .run();
+async fn serve(store: Store, args: args::ServeArgs) -> std::io::Result<()> {
+    let client = create_client(&args.client)?;
+    let (progress_sender, _) = broadcast::channel(256);
+    let workers = if args.read_only {
+        Vec::new()
+    } else {
+        spawn_workers(&store, &client, args.crawl, &progress_sender, &RUN_STATE)
+    };
+    let admin = args
+        .admin_token
+        .map(|token| AdminConfig::new(token, args.export_salt));
+    let server = server(
+        store,
+        client,
+        progress_sender,
+        args.read_only,
+        admin,
+        args.address,
+    )?;
     let handle = server.handle();
     ctrlc::set_handler(move || {
         drop(handle.stop(true)); // stop is initiated anyways
         RUN_STATE.store(false, Ordering::Relaxed);
     })
     .expect("Unable to set interrrupt handler");
-    let (collection_res, item_res, progress_res, server_res) =
-        join!(collection_worker, item_worker, progress_manager, server);
-    collection_res.unwrap();
-    item_res.unwrap();
-    progress_res.unwrap();
+    let (worker_results, server_res) = join!(future::join_all(workers), server);
+    for result in worker_results {
+        result.unwrap();
+    }
     server_res.unwrap();
     Ok(())
 }
 
-#[derive(Debug, Snafu)]
-pub enum Error {
-    #[snafu(display("Network error: {:?}", source))]
-    NetworkError { source: reqwest::Error },
-
-    #[snafu(display("Resource not found"))]
-    NotFoundError,
-
-    #[snafu(display("Serialization error: {:?}", source))]
-    SerializationError { source: serde_json::Error },
-
-    #[snafu(display("Error opening database: {:?}", source))]
-    DbOpenError { source: rusqlite::Error },
-
-    #[snafu(display("Error preparing database statement: {:?}", source))]
-    DbPrepareError { source: rusqlite::Error },
-
-    #[snafu(display("Database read error: {:?}", source))]
-    DbReadError { source: rusqlite::Error },
-
-    #[snafu(display("Database write error: {:?}", source))]
-    DbWriteError { source: rusqlite::Error },
-
-    #[snafu(display("Error opening database: {:?}", source))]
-    DbPoolError { source: r2d2::Error },
-
-    #[snafu(display("No row returned from database"))]
-    DbResultError,
-
-    #[snafu(display("Rate limit reached"))]
-    RateLimit,
+async fn crawl(store: Store, args: args::ClientArgs) -> std::io::Result<()> {
+    let client = create_client(&args)?;
+    // nobody listens to progress without the server
+    let (progress_sender, _) = broadcast::channel(1);
+    let workers = spawn_workers(&store, &client, true, &progress_sender, &RUN_STATE);
+    ctrlc::set_handler(move || RUN_STATE.store(false, Ordering::Relaxed))
+        .expect("Unable to set interrrupt handler");
+    for result in future::join_all(workers).await {
+        result.unwrap();
+    }
+    Ok(())
+}
 
-    #[snafu(display("Page content error"))]
-    PageError,
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let args = args::Args::parse();
+    let read_only = matches!(&args.command, args::Command::Serve(serve) if serve.read_only);
+    let result = match open_store(&args, read_only) {
+        Ok(store) => match args.command {
+            args::Command::Serve(serve_args) => return serve(store, serve_args).await,
+            args::Command::Crawl(client_args) => return crawl(store, client_args).await,
+            args::Command::Recommend(recommend_args) => {
+                commands::recommend(&store, &recommend_args)
+            }
+            args::Command::Stats(stats_args) => commands::stats(&store, &stats_args),
+            args::Command::Export(export_args) => commands::export(&store, &export_args),
+            args::Command::Import(import_args) => commands::import(&store, &import_args),
+            args::Command::Evaluate(evaluation_args) => evaluate(&store, &evaluation_args.config())
+                .map(|report| {
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                }),
+            args::Command::Migrate(migrate_args) => commands::migrate(&store, &migrate_args),
+        },
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        println!("Error: {err}");
+        std::process::exit(1);
+    }
+    Ok(())
 }
@@ -0,0 +1,180 @@
+use crate::{DbReadSnafu, DbWriteSnafu, Error, SchemaOutdatedSnafu, SchemaTooNewSnafu};
+use rusqlite::{Connection, TransactionBehavior};
+use snafu::{ensure, ResultExt};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    sql: &'static str,
+}
+
+// Applied in order, each in its own transaction. Never edit a migration that was released,
+// add a new one instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "collects_item_index",
+        sql: include_str!("migrations/0002_collects_item_index.sql"),
+    },
+];
+
+pub const LATEST_VERSION: i64 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+pub fn schema_version(db: &Connection) -> Result<i64, Error> {
+    db.query_row("pragma user_version", [], |row| row.get(0))
+        .context(DbReadSnafu)
+}
+
+fn apply(
+    db: &mut Connection,
+    migrations: &'static [Migration],
+    dry_run: bool,
+) -> Result<Vec<Migration>, Error> {
+    let version = schema_version(db)?;
+    let latest = migrations.last().map_or(0, |migration| migration.version);
+    ensure!(version <= latest, SchemaTooNewSnafu { version, latest });
+    let pending = migrations
+        .iter()
+        .filter(|migration| migration.version > version)
+        .copied()
+        .collect::<Vec<_>>();
+    if dry_run {
+        return Ok(pending);
+    }
+    for migration in &pending {
+        let tx = db
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context(DbWriteSnafu)?;
+        tx.execute_batch(migration.sql).context(DbWriteSnafu)?;
+        // pragmas can't take parameters
+        tx.execute_batch(&format!("pragma user_version = {}", migration.version))
+            .context(DbWriteSnafu)?;
+        tx.commit().context(DbWriteSnafu)?;
+    }
+    Ok(pending)
+}
+
+// Brings the schema up to date, returns the migrations that were (or with dry_run would be) applied
+pub fn migrate(db: &mut Connection, dry_run: bool) -> Result<Vec<Migration>, Error> {
+    apply(db, MIGRATIONS, dry_run)
+}
+
+// For connections that can't migrate, like read only ones
+pub fn check_version(db: &Connection) -> Result<(), Error> {
+    let version = schema_version(db)?;
+    let latest = LATEST_VERSION;
+    ensure!(version <= latest, SchemaTooNewSnafu { version, latest });
+    ensure!(version == latest, SchemaOutdatedSnafu { version, latest });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            name: "first",
+            sql: "create table a (x integer);",
+        },
+        Migration {
+            version: 2,
+            name: "broken",
+            sql: "alter table a add column y integer; insert into missing values (1);",
+        },
+    ];
+
+    #[test]
+    fn versions_are_ordered() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as i64 + 1);
+        }
+    }
+
+    #[test]
+    fn applies_pending_steps_in_transactions() {
+        let mut db = Connection::open_in_memory().unwrap();
+        let pending = apply(&mut db, TEST_MIGRATIONS, true).unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(schema_version(&db).unwrap(), 0);
+        // the second step fails and leaves the first one in place
+        assert!(apply(&mut db, TEST_MIGRATIONS, false).is_err());
+        assert_eq!(schema_version(&db).unwrap(), 1);
+        assert!(db.prepare("select y from a").is_err());
+
+        db.execute_batch("pragma user_version = 3").unwrap();
+        assert!(matches!(
+            apply(&mut db, TEST_MIGRATIONS, false),
+            Err(Error::SchemaTooNewError {
+                version: 3,
+                latest: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn initial_migration_accepts_existing_databases() {
+        let mut db = Connection::open_in_memory().unwrap();
+        // the init.sql databases were created with, before user_version was set
+        db.execute_batch(include_str!("migrations/fixtures/init.sql"))
+            .unwrap();
+        db.execute_batch(
+            "insert into item values (7, 'album', 'Title', '', 1, 'Band', null, 0, 100);
+            insert into collector values (1, 'a', 'A', null, 200);
+            insert into collects values (1, 7);
+            insert into collected_by values (7, 1);
+            insert into item_collected_by_queue values (7);
+            insert into collector_collection_queue values (1);
+            insert into collection_target values (1, 0, 1, 1, 0);",
+        )
+        .unwrap();
+        assert_eq!(migrate(&mut db, false).unwrap().len(), MIGRATIONS.len());
+        assert!(migrate(&mut db, false).unwrap().is_empty());
+        check_version(&db).unwrap();
+
+        let jobs: Vec<(String, i64)> = db
+            .prepare("select kind, target_id from job order by kind")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            jobs,
+            vec![("collector".to_string(), 1), ("item".to_string(), 7)]
+        );
+        assert!(db.prepare("select * from item_collected_by_queue").is_err());
+        let history: (i64, i64) = db
+            .query_row(
+                "select first_seen, last_seen from collection_history where fan_id = 1 and item_id = 7",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(history, (200, 200));
+        let queued: i64 = db
+            .query_row(
+                "select count(*) from cooccurrence_queue where fan_id = 1",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(queued, 1);
+        let kept: i64 = db
+            .query_row(
+                "select (select count(*) from collects) + (select count(*) from collected_by)
+                    + (select count(*) from collection_target)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(kept, 3);
+    }
+}
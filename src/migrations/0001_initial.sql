@@ -0,0 +1,181 @@
+-- The schema from before migrations existed. Databases created back then are at user_version 0
+-- and already contain some or all of it, so this step has to stay idempotent.
+
+create table if not exists item (
+    item_id integer not null primary key,
+    item_type text not null,
+    item_title text not null,
+    item_url text not null,
+    band_id integer not null,
+    band_name text not null,
+    token text,
+    also_collected_count integer not null,
+    last_updated integer not null
+) strict;
+
+create index if not exists item_last_updated on item(last_updated);
+
+create table if not exists collector (
+    fan_id integer not null primary key,
+    username text not null unique,
+    name text not null,
+    token text,
+    last_updated integer not null
+) strict;
+
+create unique index if not exists collector_username on collector(username);
+create index if not exists collector_last_updated on collector(last_updated);
+
+create table if not exists collected_by (
+    item_id integer not null references item on delete cascade,
+    fan_id integer not null references collector on delete cascade,
+    primary key (item_id, fan_id)
+) strict;
+
+-- the current collections, removed items are only kept in collection_history
+create table if not exists collects (
+    fan_id integer not null references collector on delete cascade,
+    item_id integer not null references item on delete cascade,
+    primary key (fan_id, item_id)
+) strict;
+
+-- every item that was ever part of a collection, all times are unix timestamps
+create table if not exists collection_history (
+    fan_id integer not null references collector on delete cascade,
+    item_id integer not null references item on delete cascade,
+    purchased integer, -- as reported by bandcamp
+    first_seen integer not null,
+    last_seen integer not null,
+    removed integer, -- set while the item is missing from the collection
+    primary key (fan_id, item_id)
+) strict;
+
+create index if not exists collection_history_purchased
+on collection_history(fan_id, coalesce(purchased, first_seen));
+
+-- collections fetched before the history existed
+insert or ignore into collection_history (fan_id, item_id, first_seen, last_seen)
+select fan_id, item_id, last_updated, last_updated from collects
+join collector using (fan_id);
+
+-- fetches requested by targets, target_id is an item_id or a fan_id depending on kind
+create table if not exists job (
+    kind text not null,
+    target_id integer not null,
+    state text not null default 'queued', -- queued or failed
+    attempts integer not null default 0,
+    last_error text,
+    next_attempt integer not null default (unixepoch('now')),
+    priority integer not null default 0,
+    requested_by integer, -- fan_id of the collection_target that needs this job
+    primary key (kind, target_id)
+) strict;
+
+create index if not exists job_next_attempt on job(kind, state, next_attempt);
+
+-- moving average and variance of how long a job of each kind takes,
+-- including pagination and rate limit pauses
+create table if not exists job_stats (
+    kind text not null primary key,
+    mean real not null,
+    variance real not null
+) strict;
+
+-- when a target last had one of its jobs picked, so concurrent targets take turns
+create table if not exists target_turn (
+    fan_id integer not null primary key,
+    turn integer not null
+) strict;
+
+-- move over queues from before the job table existed
+create table if not exists item_collected_by_queue (
+    item_id integer not null primary key references item on delete cascade
+) strict;
+
+create table if not exists collector_collection_queue (
+    fan_id integer not null primary key references collector on delete cascade
+) strict;
+
+insert or ignore into job (kind, target_id)
+select 'item', item_id from item_collected_by_queue;
+
+insert or ignore into job (kind, target_id)
+select 'collector', fan_id from collector_collection_queue;
+
+drop table item_collected_by_queue;
+drop table collector_collection_queue;
+
+create table if not exists collection_target (
+    fan_id integer not null primary key references collector on delete cascade,
+    stage integer not null,
+    count_left integer not null, -- technically redundant, but not that cheap to compute
+    count_total integer not null,
+    eta integer not null -- technically redundant
+) strict;
+
+-- pairs of items collected by the same fan, kept in sync with collects by the cooccurrence worker
+create table if not exists item_cooccurrence (
+    item_id integer not null,
+    other_id integer not null,
+    count integer not null,
+    primary key (item_id, other_id)
+) strict, without rowid;
+
+create index if not exists item_cooccurrence_count on item_cooccurrence(item_id, count);
+
+create table if not exists item_occurrence (
+    item_id integer not null primary key,
+    count integer not null
+) strict;
+
+-- the part of collects that is already counted in item_cooccurrence
+create table if not exists cooccurrence_collects (
+    fan_id integer not null,
+    item_id integer not null,
+    primary key (fan_id, item_id)
+) strict;
+
+create table if not exists cooccurrence_queue (
+    fan_id integer not null primary key
+) strict;
+
+create trigger if not exists collects_insert_cooccurrence after insert on collects
+begin
+    insert or ignore into cooccurrence_queue (fan_id) values (new.fan_id);
+end;
+
+create trigger if not exists collects_delete_cooccurrence after delete on collects
+begin
+    insert or ignore into cooccurrence_queue (fan_id) values (old.fan_id);
+end;
+
+-- collections crawled before the cooccurrence table existed
+insert or ignore into cooccurrence_queue (fan_id)
+select distinct fan_id from collects
+where fan_id not in (select fan_id from cooccurrence_collects);
+
+create table if not exists dismissed_item (
+    fan_id integer not null references collector on delete cascade,
+    item_id integer not null references item on delete cascade,
+    primary key (fan_id, item_id)
+) strict;
+
+create table if not exists dismissed_band (
+    fan_id integer not null references collector on delete cascade,
+    band_id integer not null,
+    primary key (fan_id, band_id)
+) strict;
+
+-- collection size at the last refresh, and how much it grew since the refresh before
+create table if not exists collector_activity (
+    fan_id integer not null primary key references collector on delete cascade,
+    collection_size integer not null,
+    new_items integer not null
+) strict;
+
+-- same semantics as collects, but for the wishlist
+create table if not exists wishes (
+    fan_id integer not null references collector on delete cascade,
+    item_id integer not null references item on delete cascade,
+    primary key (fan_id, item_id)
+) strict;
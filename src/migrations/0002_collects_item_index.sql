@@ -0,0 +1,2 @@
+-- looking up the collectors of an item in collects, as explanations of item based rankings do
+create index if not exists collects_item on collects(item_id);
@@ -0,0 +1,78 @@
+use crate::client::BandcampClient;
+use crate::rate_limiter::RateLimiter;
+use actix_web::dev::ServerHandle;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use std::net::TcpListener;
+use std::path::Path;
+
+// Recorded bandcamp responses, `{{base_url}}` is replaced with the mock server's url
+const ROUTES: &[(&str, &str, &str)] = &[
+    ("GET", "/testfan", "fan_page.html"),
+    (
+        "POST",
+        "/api/fancollection/1/collection_items",
+        "collection_items.json",
+    ),
+    ("GET", "/album/first", "album_page.html"),
+    ("POST", "/api/tralbumcollectors/2/thumbs", "thumbs.json"),
+];
+
+const RATE_LIMITED_PATH: &str = "/ratelimited";
+
+pub struct MockServer {
+    pub base_url: String,
+    handle: ServerHandle,
+}
+
+async fn respond(req: HttpRequest, base_url: web::Data<String>) -> HttpResponse {
+    if req.path() == RATE_LIMITED_PATH {
+        return HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", "0"))
+            .finish();
+    }
+    let route = ROUTES
+        .iter()
+        .find(|(method, path, _)| req.method().as_str() == *method && req.path() == *path);
+    match route {
+        Some((_, _, file)) => {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("fixtures")
+                .join(file);
+            let body = std::fs::read_to_string(path)
+                .expect("Unable to read fixture")
+                .replace("{{base_url}}", base_url.get_ref());
+            HttpResponse::Ok().body(body)
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+impl MockServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to bind mock server");
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let data = web::Data::new(base_url.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .default_service(web::to(respond))
+        })
+        .workers(1)
+        .listen(listener)
+        .expect("Unable to start mock server")
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        MockServer { base_url, handle }
+    }
+
+    pub fn client(&self) -> BandcampClient {
+        BandcampClient::new(&self.base_url, RateLimiter::new(100.0, 10).unwrap())
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        drop(self.handle.stop(false)); // stop is initiated anyways
+    }
+}
@@ -1,16 +1,66 @@
-use crate::types::{target_from_row, Target};
-use crate::{DbPoolSnafu, DbPrepareSnafu, DbReadSnafu, DbWriteSnafu, Error};
+use crate::items::item_present_and_recent;
+use crate::jobs::{self, Priority};
+use crate::types::{target_from_row, ItemTarget, JobKind, Target};
+#[cfg(feature = "workers")]
+use crate::DbPoolSnafu;
+use crate::{DbPrepareSnafu, DbReadSnafu, DbWriteSnafu, Error};
 use fallible_iterator::FallibleIterator;
+#[cfg(feature = "workers")]
 use r2d2::Pool;
+#[cfg(feature = "workers")]
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{CachedStatement, Connection};
 use snafu::ResultExt;
+#[cfg(feature = "workers")]
 use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "workers")]
 use std::time::Duration;
+#[cfg(feature = "workers")]
+use tokio::sync::broadcast::Sender;
+#[cfg(feature = "workers")]
 use tokio::time::{interval, MissedTickBehavior};
 
-const STAGE_1_PER_ITEM: usize = 3; // seconds per item
-const STAGE_2_PER_ITEM: usize = 5; // seconds per collector
+// Seconds per job until real job durations were measured
+const STAGE_1_PER_ITEM: f64 = 3.0;
+const STAGE_2_PER_ITEM: f64 = 5.0;
+// Width of the eta range in standard deviations, about 95%
+const CONFIDENCE_Z: f64 = 1.96;
+
+struct Estimate {
+    eta: i64,
+    low: i64,
+    high: i64,
+}
+
+// Assumes the jobs ahead in the queue and the remaining ones take the measured average each
+fn estimate(
+    db: &Connection,
+    stage: i64,
+    requested_by: Option<i64>,
+    count_left: i64,
+) -> Result<Estimate, Error> {
+    let (kind, priority, prior) = match stage {
+        1 => (JobKind::Item, Priority::Interactive, STAGE_1_PER_ITEM),
+        2 => (JobKind::Collector, Priority::Neighbour, STAGE_2_PER_ITEM),
+        _ => {
+            return Ok(Estimate {
+                eta: 0,
+                low: 0,
+                high: 0,
+            })
+        }
+    };
+    let (mean, variance) = jobs::get_duration_stats(db, kind)?.unwrap_or((prior, prior * prior));
+    let ahead = jobs::count_jobs_ahead(db, kind, priority, requested_by, count_left)?;
+    let count = (ahead + count_left) as f64;
+    let eta = count * mean;
+    let spread = CONFIDENCE_Z * (count * variance).sqrt();
+    Ok(Estimate {
+        eta: eta.round() as i64,
+        low: (eta - spread).max(0.0).round() as i64,
+        high: (eta + spread).round() as i64,
+    })
+}
 
 fn get_ids(fan_id: i64, stmt: &mut CachedStatement) -> Result<Vec<i64>, Error> {
     let results = stmt
@@ -25,7 +75,8 @@ fn get_ids(fan_id: i64, stmt: &mut CachedStatement) -> Result<Vec<i64>, Error> {
 const SELECT_PENDING_STAGE_1_REQUIREMENTS: &str = r#"
 select item_id from collects c
 where fan_id = ? and
-(select unixepoch('now') > unixepoch(last_updated, '30 days') from item i where i.item_id = c.item_id)
+(select item_is_stale(last_updated) from item i where i.item_id = c.item_id) and
+item_id not in (select target_id from job where kind = 'item' and state = 'failed')
 "#;
 
 fn get_stage_1_requirements(db: &Connection, fan_id: i64) -> Result<Vec<i64>, Error> {
@@ -39,7 +90,9 @@ fn get_stage_1_requirements(db: &Connection, fan_id: i64) -> Result<Vec<i64>, Er
 const SELECT_PENDING_STAGE_2_REQUIREMENTS: &str = r#"
 select fan_id from collected_by c
 where item_id in (select item_id from collects where fan_id = ?) and
-(select unixepoch('now') > unixepoch(last_updated, '30 days') from collector co where co.fan_id = c.fan_id)
+(select collector_is_stale(last_updated, new_items) from collector co
+ left join collector_activity using (fan_id) where co.fan_id = c.fan_id) and
+fan_id not in (select target_id from job where kind = 'collector' and state = 'failed')
 group by fan_id
 having count(fan_id) > 1"#;
 
@@ -67,7 +120,7 @@ fn insert_target(
     stage: i64,
     count_left: usize,
     count_total: usize,
-    eta: usize,
+    eta: i64,
 ) -> Result<(), Error> {
     let mut stmt = db.prepare_cached(INSERT_TARGET).context(DbPrepareSnafu)?;
     stmt.execute((fan_id, stage, count_left, count_total, eta))
@@ -75,32 +128,10 @@ fn insert_target(
     Ok(())
 }
 
-const INSERT_TO_COLLECTED_BY_QUEUE: &str = r#"
-insert or ignore into item_collected_by_queue (item_id) values (?)"#;
-
-fn insert_to_collected_by_queue(db: &Connection, item_id: i64) -> Result<(), Error> {
-    let mut stmt = db
-        .prepare_cached(INSERT_TO_COLLECTED_BY_QUEUE)
-        .context(DbPrepareSnafu)?;
-    stmt.execute([item_id]).context(DbWriteSnafu)?;
-    Ok(())
-}
-
-const INSERT_TO_COLLECTION_QUEUE: &str = r#"
-insert or ignore into collector_collection_queue (fan_id) values (?)"#;
-
-fn insert_to_collection_queue(db: &Connection, fan_id: i64) -> Result<(), Error> {
-    let mut stmt = db
-        .prepare_cached(INSERT_TO_COLLECTION_QUEUE)
-        .context(DbPrepareSnafu)?;
-    stmt.execute([fan_id]).context(DbWriteSnafu)?;
-    Ok(())
-}
-
 const SELECT_TARGET: &str = r#"
 select * from collection_target where fan_id = ?"#;
 
-fn get_target(db: &Connection, fan_id: i64) -> Result<Target, Error> {
+pub fn get_target(db: &Connection, fan_id: i64) -> Result<Target, Error> {
     let mut stmt = db.prepare_cached(SELECT_TARGET).context(DbPrepareSnafu)?;
     let result = stmt
         .query([fan_id])
@@ -114,9 +145,18 @@ fn get_target(db: &Connection, fan_id: i64) -> Result<Target, Error> {
             count_left: 0,
             count_total: 0,
             eta: 0,
+            eta_low: 0,
+            eta_high: 0,
         }))
         .context(DbReadSnafu)?;
-    Ok(result)
+    // the queue moves on independently of the target, so the stored eta is refreshed
+    let estimate = estimate(db, result.stage, Some(fan_id), result.count_left)?;
+    Ok(Target {
+        eta: estimate.eta,
+        eta_low: estimate.low,
+        eta_high: estimate.high,
+        ..result
+    })
 }
 
 const DELETE_TARGET: &str = r#"
@@ -125,7 +165,7 @@ delete from collection_target where fan_id = ?"#;
 fn delete_target(db: &Connection, fan_id: i64) -> Result<(), Error> {
     let mut stmt = db.prepare_cached(DELETE_TARGET).context(DbPrepareSnafu)?;
     stmt.execute([fan_id]).context(DbWriteSnafu)?;
-    Ok(())
+    jobs::remove_target_turn(db, fan_id)
 }
 
 fn handle_stage_2(db: &Connection, fan_id: i64, old_count: Option<i64>) -> Result<(), Error> {
@@ -137,11 +177,17 @@ fn handle_stage_2(db: &Connection, fan_id: i64, old_count: Option<i64>) -> Resul
             2,
             requirements.len(),
             old_count.map(|v| v as usize).unwrap_or(requirements.len()),
-            requirements.len() * STAGE_2_PER_ITEM,
+            estimate(db, 2, Some(fan_id), requirements.len() as i64)?.eta,
         )?;
         if old_count.is_none() {
-            for fan_id in requirements {
-                insert_to_collection_queue(db, fan_id)?;
+            for collector in requirements {
+                jobs::enqueue(
+                    db,
+                    JobKind::Collector,
+                    collector,
+                    Priority::Neighbour,
+                    Some(fan_id),
+                )?;
             }
         }
     } else {
@@ -159,11 +205,17 @@ fn handle_stage_1(db: &Connection, fan_id: i64, old_count: Option<i64>) -> Resul
             1,
             requirements.len(),
             old_count.map(|v| v as usize).unwrap_or(requirements.len()),
-            requirements.len() * STAGE_1_PER_ITEM,
+            estimate(db, 1, Some(fan_id), requirements.len() as i64)?.eta,
         )?;
         if old_count.is_none() {
             for item_id in requirements {
-                insert_to_collected_by_queue(db, item_id)?;
+                jobs::enqueue(
+                    db,
+                    JobKind::Item,
+                    item_id,
+                    Priority::Interactive,
+                    Some(fan_id),
+                )?;
             }
         }
     } else {
@@ -177,19 +229,98 @@ pub fn add_target(db: &Connection, fan_id: i64) -> Result<Target, Error> {
     get_target(db, fan_id)
 }
 
-pub fn update_target(db: &Connection, fan_id: i64) -> Result<(), Error> {
+// Returns the new state if the stage or the number of pending requirements changed
+#[cfg(feature = "workers")]
+fn update_target(db: &Connection, fan_id: i64) -> Result<Option<Target>, Error> {
     let target = get_target(db, fan_id)?;
     if target.stage == 1 {
         handle_stage_1(db, fan_id, Some(target.count_total))?;
     } else if target.stage == 2 {
         handle_stage_2(db, fan_id, Some(target.count_total))?;
     }
-    Ok(())
+    let updated = get_target(db, fan_id)?;
+    if updated.stage != target.stage || updated.count_left != target.count_left {
+        Ok(Some(updated))
+    } else {
+        Ok(None)
+    }
 }
 
+const SELECT_PENDING_ITEM_STAGE_2_REQUIREMENTS: &str = r#"
+select fan_id from collected_by c
+where item_id = ? and
+(select collector_is_stale(last_updated, new_items) from collector co
+ left join collector_activity using (fan_id) where co.fan_id = c.fan_id) and
+fan_id not in (select target_id from job where kind = 'collector' and state = 'failed')"#;
+
+fn get_item_stage_2_requirements(db: &Connection, item_id: i64) -> Result<Vec<i64>, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_PENDING_ITEM_STAGE_2_REQUIREMENTS)
+        .context(DbPrepareSnafu)?;
+    let results = get_ids(item_id, &mut stmt)?;
+    Ok(results)
+}
+
+const SELECT_ITEM_COLLECTOR_COUNT: &str = r#"
+select count(*) from collected_by where item_id = ?"#;
+
+fn get_item_collector_count(db: &Connection, item_id: i64) -> Result<i64, Error> {
+    let mut stmt = db
+        .prepare_cached(SELECT_ITEM_COLLECTOR_COUNT)
+        .context(DbPrepareSnafu)?;
+    let result = stmt
+        .query_row([item_id], |row| row.get(0))
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
+
+// Item targets are cheap to compute, so they are not persisted
+pub fn add_item_target(db: &Connection, item_id: i64) -> Result<ItemTarget, Error> {
+    if !item_present_and_recent(db, item_id)? {
+        jobs::enqueue(db, JobKind::Item, item_id, Priority::Interactive, None)?;
+        let estimate = estimate(db, 1, None, 1)?;
+        return Ok(ItemTarget {
+            item_id,
+            stage: 1,
+            count_left: 1,
+            count_total: 1,
+            eta: estimate.eta,
+            eta_low: estimate.low,
+            eta_high: estimate.high,
+        });
+    }
+    let requirements = get_item_stage_2_requirements(db, item_id)?;
+    if requirements.is_empty() {
+        return Ok(ItemTarget {
+            item_id,
+            stage: 3,
+            count_left: 0,
+            count_total: 0,
+            eta: 0,
+            eta_low: 0,
+            eta_high: 0,
+        });
+    }
+    for fan_id in &requirements {
+        jobs::enqueue(db, JobKind::Collector, *fan_id, Priority::Neighbour, None)?;
+    }
+    let estimate = estimate(db, 2, None, requirements.len() as i64)?;
+    Ok(ItemTarget {
+        item_id,
+        stage: 2,
+        count_left: requirements.len() as i64,
+        count_total: get_item_collector_count(db, item_id)?,
+        eta: estimate.eta,
+        eta_low: estimate.low,
+        eta_high: estimate.high,
+    })
+}
+
+#[cfg(feature = "workers")]
 const GET_TARGETS: &str = r#"
 select fan_id from collection_target"#;
 
+#[cfg(feature = "workers")]
 fn get_targets(db: &Connection) -> Result<Vec<i64>, Error> {
     let mut stmt = db.prepare_cached(GET_TARGETS).context(DbPrepareSnafu)?;
     let result = stmt
@@ -201,8 +332,10 @@ fn get_targets(db: &Connection) -> Result<Vec<i64>, Error> {
     Ok(result)
 }
 
+#[cfg(feature = "workers")]
 pub async fn progress_manager(
     db: &Pool<SqliteConnectionManager>,
+    updates: &Sender<Target>,
     run_state: &AtomicBool,
 ) -> Result<(), Error> {
     let mut timer = interval(Duration::from_secs(1));
@@ -210,7 +343,10 @@ pub async fn progress_manager(
     while run_state.load(Ordering::Relaxed) {
         let conn = db.get().context(DbPoolSnafu)?;
         for target in get_targets(&conn)? {
-            update_target(&conn, target)?
+            if let Some(target) = update_target(&conn, target)? {
+                // nobody might be listening, which is fine
+                let _ = updates.send(target);
+            }
         }
         drop(conn);
         timer.tick().await;
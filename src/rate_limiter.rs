@@ -0,0 +1,192 @@
+use crate::{Error, InvalidRateSnafu};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use snafu::ensure;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+pub const DEFAULT_REQUESTS_PER_SECOND: f64 = 1.0;
+pub const DEFAULT_BURST: u32 = 5;
+
+// Backoff used when bandcamp doesn't send a Retry-After header, a sent one is always honoured
+const BASE_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+// The rate is halved on every 429 and slowly recovers on success, but never drops below this fraction
+const MIN_RATE_FRACTION: f64 = 1.0 / 32.0;
+const RATE_INCREASE_FRACTION: f64 = 1.0 / 50.0;
+
+struct State {
+    tokens: f64,
+    rate: f64,
+    last_refill: Instant,
+    blocked_until: Option<Instant>,
+    backoff_exponent: u32,
+}
+
+// Token bucket shared by all scrapers
+pub struct RateLimiter {
+    max_rate: f64,
+    burst: f64,
+    state: Mutex<State>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(DEFAULT_REQUESTS_PER_SECOND, DEFAULT_BURST).unwrap()
+    }
+}
+
+impl RateLimiter {
+    /// Fails unless requests_per_second is finite and greater than 0
+    pub fn new(requests_per_second: f64, burst: u32) -> Result<Self, Error> {
+        ensure!(
+            requests_per_second.is_finite() && requests_per_second > 0.0,
+            InvalidRateSnafu {
+                rate: requests_per_second
+            }
+        );
+        let burst = burst.max(1) as f64;
+        Ok(RateLimiter {
+            max_rate: requests_per_second,
+            burst,
+            state: Mutex::new(State {
+                tokens: burst,
+                rate: requests_per_second,
+                last_refill: Instant::now(),
+                blocked_until: None,
+                backoff_exponent: 0,
+            }),
+        })
+    }
+
+    // Either takes a token, or returns how long to wait for the next one
+    fn try_acquire(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if let Some(blocked_until) = state.blocked_until {
+            if blocked_until > now {
+                return Some(blocked_until - now);
+            }
+            state.blocked_until = None;
+            state.last_refill = now;
+        }
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * state.rate).min(self.burst);
+        state.last_refill = now;
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - state.tokens) / state.rate))
+        }
+    }
+
+    pub async fn acquire(&self) {
+        while let Some(wait) = self.try_acquire() {
+            sleep(wait).await;
+        }
+    }
+
+    pub fn on_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.backoff_exponent = 0;
+        state.rate = (state.rate + self.max_rate * RATE_INCREASE_FRACTION).min(self.max_rate);
+    }
+
+    pub fn on_rate_limited(&self, retry_after: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        let backoff = retry_after.unwrap_or_else(|| {
+            (BASE_BACKOFF * 2u32.pow(state.backoff_exponent.min(16)))
+                .min(MAX_BACKOFF)
+                .mul_f64(1.0 + rand::thread_rng().gen_range(0.0..0.5))
+        });
+        state.backoff_exponent += 1;
+        state.rate = (state.rate / 2.0).max(self.max_rate * MIN_RATE_FRACTION);
+        state.tokens = 0.0;
+        let blocked_until = Instant::now() + backoff;
+        state.blocked_until = Some(
+            state
+                .blocked_until
+                .map_or(blocked_until, |b| b.max(blocked_until)),
+        );
+        println!(
+            "Rate limited, backing off for {:.1} seconds at {:.2} requests per second",
+            backoff.as_secs_f64(),
+            state.rate
+        );
+    }
+}
+
+// Retry-After is either a number of seconds or an http date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(limiter: &RateLimiter) -> f64 {
+        limiter.state.lock().unwrap().rate
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn adapts_rate_to_rate_limits() {
+        let limiter = RateLimiter::new(4.0, 2).unwrap();
+        assert!(limiter.try_acquire().is_none());
+        assert!(limiter.try_acquire().is_none());
+        assert!(limiter.try_acquire().is_some());
+        limiter.on_rate_limited(Some(Duration::from_secs(60)));
+        assert_eq!(rate(&limiter), 2.0);
+        assert!(limiter.try_acquire().unwrap() >= Duration::from_secs(59));
+        limiter.on_success();
+        assert!(rate(&limiter) > 2.0);
+        for _ in 0..100 {
+            limiter.on_success();
+        }
+        assert_eq!(rate(&limiter), 4.0);
+    }
+
+    #[test]
+    fn honours_long_retry_after() {
+        let limiter = RateLimiter::new(4.0, 2).unwrap();
+        limiter.on_rate_limited(Some(Duration::from_secs(3600)));
+        assert!(limiter.try_acquire().unwrap() >= Duration::from_secs(3599));
+        // without one the backoff is capped
+        let limiter = RateLimiter::new(4.0, 2).unwrap();
+        for _ in 0..10 {
+            limiter.on_rate_limited(None);
+        }
+        assert!(limiter.try_acquire().unwrap() <= MAX_BACKOFF.mul_f64(1.5));
+    }
+
+    #[test]
+    fn rejects_non_positive_rates() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                RateLimiter::new(rate, 2),
+                Err(Error::InvalidRateError { .. })
+            ));
+        }
+    }
+}
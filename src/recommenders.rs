@@ -0,0 +1,165 @@
+use crate::cooccurrence::{get_cooccurrences, get_counted_items, get_occurrence};
+use crate::Error;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+pub trait Recommender: Send + Sync {
+    /// Whether `score` uses the collections of neighbouring collectors, loading them is expensive
+    fn needs_neighbours(&self) -> bool {
+        true
+    }
+
+    /// Scores every candidate item, given the user's collection and the collections of all
+    /// collectors sharing at least two items with the user
+    fn score(
+        &self,
+        db: &Connection,
+        fan_id: i64,
+        collection: &HashSet<i64>,
+        neighbours: &HashMap<i64, HashSet<i64>>,
+    ) -> Result<HashMap<i64, f64>, Error>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "server", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    #[default]
+    Overlap,
+    Cosine,
+    Jaccard,
+    Item,
+}
+
+impl Strategy {
+    pub fn recommender(self, similar_boost: f64, popularity_penalty: f64) -> Box<dyn Recommender> {
+        match self {
+            Strategy::Overlap => Box::new(OverlapPower {
+                similar_boost,
+                popularity_penalty,
+            }),
+            Strategy::Cosine => Box::new(UserSimilarity {
+                similar_boost,
+                similarity: cosine,
+            }),
+            Strategy::Jaccard => Box::new(UserSimilarity {
+                similar_boost,
+                similarity: jaccard,
+            }),
+            Strategy::Item => Box::new(ItemCooccurrence),
+        }
+    }
+}
+
+pub struct OverlapPower {
+    pub similar_boost: f64,
+    pub popularity_penalty: f64,
+}
+
+impl Recommender for OverlapPower {
+    fn score(
+        &self,
+        _db: &Connection,
+        _fan_id: i64,
+        collection: &HashSet<i64>,
+        neighbours: &HashMap<i64, HashSet<i64>>,
+    ) -> Result<HashMap<i64, f64>, Error> {
+        let mut count: HashMap<i64, f64> = HashMap::new();
+        for user in neighbours.values() {
+            let mult = (user.intersection(collection).count() as f64).powf(self.similar_boost);
+            if mult > 1.0 {
+                // whale collectors own a bit of everything, so their vote is worth less
+                let weight = mult / (user.len() as f64).powf(self.popularity_penalty);
+                for item in user.difference(collection) {
+                    *count.entry(*item).or_default() += weight;
+                }
+            }
+        }
+        Ok(count)
+    }
+}
+
+fn cosine(collection: &HashSet<i64>, user: &HashSet<i64>) -> f64 {
+    let overlap = user.intersection(collection).count() as f64;
+    overlap / ((collection.len() * user.len()) as f64).sqrt()
+}
+
+fn jaccard(collection: &HashSet<i64>, user: &HashSet<i64>) -> f64 {
+    let overlap = user.intersection(collection).count();
+    overlap as f64 / (collection.len() + user.len() - overlap) as f64
+}
+
+/// Weighs each neighbour by a normalized similarity, so large collections don't dominate
+pub struct UserSimilarity {
+    pub similar_boost: f64,
+    pub similarity: fn(&HashSet<i64>, &HashSet<i64>) -> f64,
+}
+
+impl Recommender for UserSimilarity {
+    fn score(
+        &self,
+        _db: &Connection,
+        _fan_id: i64,
+        collection: &HashSet<i64>,
+        neighbours: &HashMap<i64, HashSet<i64>>,
+    ) -> Result<HashMap<i64, f64>, Error> {
+        let mut count: HashMap<i64, f64> = HashMap::new();
+        for user in neighbours.values() {
+            let weight = (self.similarity)(collection, user).powf(self.similar_boost);
+            for item in user.difference(collection) {
+                *count.entry(*item).or_default() += weight;
+            }
+        }
+        Ok(count)
+    }
+}
+
+// Only the strongest pairs of each owned item are considered, which keeps this fast on large crawls
+const COOCCURRENCES_PER_ITEM: usize = 200;
+
+/// Item based: sums the cosine normalized co-occurrence of a candidate with each owned item,
+/// read from the precomputed co-occurrence table
+pub struct ItemCooccurrence;
+
+impl Recommender for ItemCooccurrence {
+    fn needs_neighbours(&self) -> bool {
+        false
+    }
+
+    fn score(
+        &self,
+        db: &Connection,
+        fan_id: i64,
+        collection: &HashSet<i64>,
+        _neighbours: &HashMap<i64, HashSet<i64>>,
+    ) -> Result<HashMap<i64, f64>, Error> {
+        // the fan's own pairs are part of the table, but must not vote for themselves
+        let own = get_counted_items(db, fan_id)?;
+        let mut occurrences: HashMap<i64, f64> = HashMap::new();
+        let mut occurrence = |item_id: i64| -> Result<f64, Error> {
+            if let Some(value) = occurrences.get(&item_id) {
+                return Ok(*value);
+            }
+            let value = (get_occurrence(db, item_id)? - own.contains(&item_id) as i64) as f64;
+            occurrences.insert(item_id, value);
+            Ok(value)
+        };
+        let mut count: HashMap<i64, f64> = HashMap::new();
+        for item in collection {
+            let item_occurrence = occurrence(*item)?;
+            for (candidate, together) in get_cooccurrences(db, *item, COOCCURRENCES_PER_ITEM)? {
+                if collection.contains(&candidate) {
+                    continue;
+                }
+                let together = together - (own.contains(item) && own.contains(&candidate)) as i64;
+                if together <= 0 {
+                    continue;
+                }
+                let norm = (item_occurrence * occurrence(candidate)?).sqrt();
+                *count.entry(candidate).or_default() += together as f64 / norm;
+            }
+        }
+        Ok(count)
+    }
+}
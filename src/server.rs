@@ -0,0 +1,823 @@
+use crate::client::{self, BandcampClient};
+use crate::export::{self, ExportFormat, ExportOptions, ExportTable};
+use crate::store::Store;
+use crate::types::{self, Target};
+use crate::{
+    analyze, collectors, dismissals, feed, freshness, items, jobs, progress_manager, recommenders,
+    DbPoolSnafu, Error, NotFoundSnafu,
+};
+use actix_web::dev::Server;
+use actix_web::http::header::{
+    CacheControl, CacheDirective, ContentDisposition, ContentType, AUTHORIZATION,
+};
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer};
+use futures_util::{future, stream, StreamExt};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::convert::Infallible;
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio::task::spawn_blocking;
+use tokio::time::timeout;
+
+type DataType = web::Data<Store>;
+type CacheType = web::Data<analyze::RankingCache>;
+type ClientType = web::Data<client::BandcampClient>;
+type ProgressType = web::Data<broadcast::Sender<types::Target>>;
+type AdminType = web::Data<AdminConfig>;
+
+/// Enables the /api/admin endpoints, which expect `Authorization: Bearer <token>`
+pub struct AdminConfig {
+    token: String,
+    export_salt: String,
+}
+
+impl AdminConfig {
+    /// Without a salt a random one is used, so anonymized exports only match within one run
+    pub fn new(token: String, export_salt: Option<String>) -> Self {
+        let export_salt = export_salt.unwrap_or_else(|| {
+            rand::random::<[u8; 16]>()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect()
+        });
+        AdminConfig { token, export_salt }
+    }
+
+    // compares digests, so the time taken says nothing about the token
+    fn authorized(&self, request: &HttpRequest) -> bool {
+        let Some(token) = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+        Sha256::digest(token) == Sha256::digest(&self.token)
+    }
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    username: String,
+}
+
+#[get("/api/get_status")]
+async fn get_status(query: web::Query<UserInfo>, data: DataType) -> HttpResponse {
+    let conn = data.get().unwrap();
+    let fan_id = match collectors::get_fan_id_for_username(&conn, &query.username) {
+        Ok(Some(fan_id)) => fan_id,
+        Ok(Option::None) => {
+            return HttpResponse::NotFound().body("User not found");
+        }
+        Err(err) => {
+            println!("Error getting status for user: {err}");
+            return HttpResponse::InternalServerError().body("Internal server error");
+        }
+    };
+    match progress_manager::add_target(&conn, fan_id) {
+        Ok(target) => HttpResponse::Ok().body(serde_json::to_string(&target).unwrap()),
+        Err(err) => {
+            println!("Error getting status for user: {err}");
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+fn progress_event(target: &types::Target) -> web::Bytes {
+    let event = if target.stage == 3 {
+        "ready"
+    } else {
+        "progress"
+    };
+    let data = serde_json::to_string(target).unwrap();
+    web::Bytes::from(format!("event: {event}\ndata: {data}\n\n"))
+}
+
+#[get("/api/status_stream")]
+async fn status_stream(
+    query: web::Query<UserInfo>,
+    data: DataType,
+    progress: ProgressType,
+) -> HttpResponse {
+    // subscribe first, so no update between computing the target and listening is lost
+    let receiver = progress.subscribe();
+    let result = data.get().context(DbPoolSnafu).and_then(|conn| {
+        let fan_id =
+            collectors::get_fan_id_for_username(&conn, &query.username)?.context(NotFoundSnafu)?;
+        progress_manager::add_target(&conn, fan_id)
+    });
+    let target = match result {
+        Ok(target) => target,
+        Err(Error::NotFoundError) => return HttpResponse::NotFound().body("User not found"),
+        Err(err) => {
+            println!("Error getting status for user: {err}");
+            return HttpResponse::InternalServerError().body("Internal server error");
+        }
+    };
+    let fan_id = target.fan_id;
+    let initial = stream::once(future::ready(Ok::<_, Infallible>(progress_event(&target))));
+    // the state is None once the ready event was sent
+    let state = (target.stage != 3).then_some(receiver);
+    let updates = stream::unfold(state, move |receiver| {
+        let data = data.clone();
+        async move {
+            let mut receiver = receiver?;
+            loop {
+                let target = match timeout(KEEP_ALIVE_INTERVAL, receiver.recv()).await {
+                    Err(_) => {
+                        let keep_alive = web::Bytes::from_static(b": keep-alive\n\n");
+                        return Some((Ok(keep_alive), Some(receiver)));
+                    }
+                    Ok(Ok(target)) if target.fan_id == fan_id => target,
+                    Ok(Ok(_)) => continue,
+                    // missed some updates, possibly the last one
+                    Ok(Err(RecvError::Lagged(_))) => {
+                        match data
+                            .get()
+                            .context(DbPoolSnafu)
+                            .and_then(|conn| progress_manager::get_target(&conn, fan_id))
+                        {
+                            Ok(target) => target,
+                            Err(_) => return None,
+                        }
+                    }
+                    Ok(Err(RecvError::Closed)) => return None,
+                };
+                let done = target.stage == 3;
+                return Some((Ok(progress_event(&target)), (!done).then_some(receiver)));
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(initial.chain(updates))
+}
+
+#[derive(Deserialize)]
+struct RefreshInfo {
+    // seconds, anything older is fetched again, including the user's neighbours
+    max_age: Option<i64>,
+}
+
+fn invalidate_user(data: &DataType, username: &str, max_age: i64) -> Result<(), Error> {
+    let conn = data.get().context(DbPoolSnafu)?;
+    let fan_id = collectors::get_fan_id_for_username(&conn, username)?.context(NotFoundSnafu)?;
+    freshness::invalidate_for_user(&conn, fan_id, max_age)
+}
+
+#[get("/api/get_user")]
+async fn get_user(
+    query: web::Query<UserInfo>,
+    refresh: web::Query<RefreshInfo>,
+    data: DataType,
+    client: ClientType,
+) -> HttpResponse {
+    let result = collectors::fetch_collection(data.get_ref(), &client, &query.username, true)
+        .await
+        .and_then(|_| match refresh.max_age {
+            Some(max_age) => invalidate_user(&data, &query.username, max_age),
+            None => Ok(()),
+        })
+        .and_then(|_| collectors::get_collection_size(data.get_ref(), &query.username));
+    match result {
+        Ok(size) => {
+            if size > 2 {
+                HttpResponse::Ok().body("User fetched successfully")
+            } else {
+                HttpResponse::NotFound()
+                    .body("User does not contain enough items (at least 2 required)")
+            }
+        }
+        Err(Error::NotFoundError) => HttpResponse::NotFound().body("User not found"),
+        Err(err) => {
+            println!("Error fetching user: {err}");
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PurchasesInfo {
+    limit: Option<usize>,
+}
+
+#[get("/api/get_purchases")]
+async fn get_purchases(
+    user: web::Query<UserInfo>,
+    query: web::Query<PurchasesInfo>,
+    data: DataType,
+) -> HttpResponse {
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    let result = data.get().context(DbPoolSnafu).and_then(|conn| {
+        let fan_id =
+            collectors::get_fan_id_for_username(&conn, &user.username)?.context(NotFoundSnafu)?;
+        collectors::get_purchases(&conn, fan_id, limit)
+    });
+    match result {
+        Ok(purchases) => HttpResponse::Ok().body(serde_json::to_string(&purchases).unwrap()),
+        Err(Error::NotFoundError) => HttpResponse::NotFound().body("User not found"),
+        Err(err) => {
+            println!("Error getting purchases for user: {err}");
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RecommendationInfo {
+    similar_boost: Option<f64>,
+    popularity_penalty: Option<f64>,
+    #[serde(default)]
+    strategy: recommenders::Strategy,
+    item_types: Option<String>,
+    #[serde(default)]
+    exclude_known_bands: bool,
+    max_per_band: Option<usize>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    #[serde(default)]
+    wishlist: analyze::WishlistMode,
+}
+
+impl RecommendationInfo {
+    fn params(&self) -> analyze::RankingParams {
+        analyze::RankingParams::new(
+            self.strategy,
+            self.similar_boost.unwrap_or(2.0),
+            self.popularity_penalty.unwrap_or(0.0),
+            self.wishlist == analyze::WishlistMode::Seed,
+        )
+    }
+
+    fn filter(&self) -> Result<analyze::RecommendationFilter, ()> {
+        let item_types = match &self.item_types {
+            Some(item_types) => Some(
+                item_types
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?,
+            ),
+            None => None,
+        };
+        Ok(analyze::RecommendationFilter {
+            item_types,
+            exclude_known_bands: self.exclude_known_bands,
+            max_per_band: self.max_per_band,
+            wishlist: self.wishlist,
+        })
+    }
+
+    fn page(&self) -> (usize, usize) {
+        (
+            self.offset.unwrap_or(0),
+            self.limit.unwrap_or(50).clamp(1, 500),
+        )
+    }
+}
+
+#[get("/api/get_recommendations")]
+async fn get_recommendations(
+    user: web::Query<UserInfo>,
+    query: web::Query<RecommendationInfo>,
+    data: DataType,
+    cache: CacheType,
+) -> HttpResponse {
+    let params = query.params();
+    let Ok(filter) = query.filter() else {
+        return HttpResponse::BadRequest().body("Unknown item type");
+    };
+    let (offset, limit) = query.page();
+    let result = spawn_blocking(move || {
+        analyze::get_user_recommendations(
+            data.get_ref(),
+            cache.get_ref(),
+            &user.username,
+            &params,
+            &filter,
+            offset,
+            limit,
+        )
+    })
+    .await
+    .unwrap();
+    match result {
+        Ok(data) => HttpResponse::Ok().body(serde_json::to_string(&data).unwrap()),
+        Err(Error::NotFoundError) => HttpResponse::NotFound().body("User not found"),
+        Err(err) => {
+            println!("Error getting recommendations for user: {err}");
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FeedInfo {
+    days: Option<i64>,
+    limit: Option<usize>,
+}
+
+impl FeedInfo {
+    fn get_feed(&self, data: DataType, username: String) -> Result<Vec<types::FeedEntry>, Error> {
+        let days = self.days.unwrap_or(30).clamp(1, 365);
+        let limit = self.limit.unwrap_or(50).clamp(1, 500);
+        analyze::get_user_feed(data.get_ref(), &username, days, limit)
+    }
+}
+
+#[get("/api/feed")]
+async fn get_feed(
+    user: web::Query<UserInfo>,
+    query: web::Query<FeedInfo>,
+    data: DataType,
+) -> HttpResponse {
+    let result = spawn_blocking(move || query.get_feed(data, user.into_inner().username))
+        .await
+        .unwrap();
+    match result {
+        Ok(entries) => HttpResponse::Ok().body(serde_json::to_string(&entries).unwrap()),
+        Err(Error::NotFoundError) => HttpResponse::NotFound().body("User not found"),
+        Err(err) => {
+            println!("Error getting feed for user: {err}");
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+#[get("/api/feed.atom")]
+async fn get_atom_feed(
+    request: HttpRequest,
+    user: web::Query<UserInfo>,
+    query: web::Query<FeedInfo>,
+    data: DataType,
+) -> HttpResponse {
+    let username = user.into_inner().username;
+    let username_copy = username.clone();
+    let result = spawn_blocking(move || query.get_feed(data, username_copy))
+        .await
+        .unwrap();
+    match result {
+        Ok(entries) => HttpResponse::Ok()
+            .content_type("application/atom+xml; charset=utf-8")
+            .body(feed::render_atom(
+                &username,
+                request.full_url().as_str(),
+                &entries,
+            )),
+        Err(Error::NotFoundError) => HttpResponse::NotFound().body("User not found"),
+        Err(err) => {
+            println!("Error getting feed for user: {err}");
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DismissInfo {
+    username: String,
+    item_id: Option<i64>,
+    band_id: Option<i64>,
+}
+
+impl DismissInfo {
+    fn dismissal(&self) -> Option<dismissals::Dismissal> {
+        match (self.item_id, self.band_id) {
+            (Some(item_id), None) => Some(dismissals::Dismissal::Item(item_id)),
+            (None, Some(band_id)) => Some(dismissals::Dismissal::Band(band_id)),
+            _ => None,
+        }
+    }
+}
+
+fn update_dismissal(
+    data: &DataType,
+    query: &DismissInfo,
+    update: fn(&rusqlite::Connection, i64, dismissals::Dismissal) -> Result<(), Error>,
+) -> HttpResponse {
+    let Some(dismissal) = query.dismissal() else {
+        return HttpResponse::BadRequest().body("Either item_id or band_id required");
+    };
+    let result = data.get().context(DbPoolSnafu).and_then(|conn| {
+        let fan_id =
+            collectors::get_fan_id_for_username(&conn, &query.username)?.context(NotFoundSnafu)?;
+        update(&conn, fan_id, dismissal)
+    });
+    match result {
+        Ok(()) => HttpResponse::Ok().body("Dismissal updated"),
+        Err(Error::NotFoundError) => HttpResponse::NotFound().body("User, item or band not found"),
+        Err(err) => {
+            println!("Error updating dismissal: {err}");
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+// Like refreshing a collection, dismissals need no login, so anyone can change them for any
+// username. Usernames are public, use --read-only to serve shared recommendations without them
+#[post("/api/dismiss")]
+async fn dismiss(query: web::Query<DismissInfo>, data: DataType) -> HttpResponse {
+    update_dismissal(&data, &query, dismissals::dismiss)
+}
+
+#[post("/api/undismiss")]
+async fn undismiss(query: web::Query<DismissInfo>, data: DataType) -> HttpResponse {
+    update_dismissal(&data, &query, dismissals::undismiss)
+}
+
+const MAX_GROUP_SIZE: usize = 10;
+
+#[derive(Deserialize)]
+struct GroupInfo {
+    usernames: String,
+    #[serde(default)]
+    aggregation: analyze::Aggregation,
+}
+
+impl GroupInfo {
+    fn usernames(&self) -> Vec<String> {
+        let mut usernames = self
+            .usernames
+            .split(',')
+            .map(str::trim)
+            .filter(|username| !username.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        usernames.sort_unstable();
+        usernames.dedup();
+        usernames
+    }
+}
+
+#[get("/api/get_group_status")]
+async fn get_group_status(
+    group: web::Query<GroupInfo>,
+    data: DataType,
+    client: ClientType,
+) -> HttpResponse {
+    let usernames = group.usernames();
+    if usernames.is_empty() || usernames.len() > MAX_GROUP_SIZE {
+        return HttpResponse::BadRequest().body("Between 1 and 10 usernames required");
+    }
+    let mut targets = Vec::new();
+    for username in &usernames {
+        let result = collectors::fetch_collection(data.get_ref(), &client, username, false)
+            .await
+            .and_then(|_| {
+                let conn = data.get().context(DbPoolSnafu)?;
+                let fan_id =
+                    collectors::get_fan_id_for_username(&conn, username)?.context(NotFoundSnafu)?;
+                progress_manager::add_target(&conn, fan_id)
+            });
+        match result {
+            Ok(target) => targets.push(target),
+            Err(Error::NotFoundError) => {
+                return HttpResponse::NotFound().body(format!("User {username} not found"));
+            }
+            Err(err) => {
+                println!("Error getting status for group: {err}");
+                return HttpResponse::InternalServerError().body("Internal server error");
+            }
+        }
+    }
+    HttpResponse::Ok().body(serde_json::to_string(&targets).unwrap())
+}
+
+#[get("/api/get_group_recommendations")]
+async fn get_group_recommendations(
+    group: web::Query<GroupInfo>,
+    query: web::Query<RecommendationInfo>,
+    data: DataType,
+    cache: CacheType,
+) -> HttpResponse {
+    let usernames = group.usernames();
+    if usernames.is_empty() || usernames.len() > MAX_GROUP_SIZE {
+        return HttpResponse::BadRequest().body("Between 1 and 10 usernames required");
+    }
+    let params = query.params();
+    let Ok(filter) = query.filter() else {
+        return HttpResponse::BadRequest().body("Unknown item type");
+    };
+    let (offset, limit) = query.page();
+    let result = spawn_blocking(move || {
+        analyze::get_group_recommendations(
+            data.get_ref(),
+            cache.get_ref(),
+            &usernames,
+            &params,
+            group.aggregation,
+            &filter,
+            offset,
+            limit,
+        )
+    })
+    .await
+    .unwrap();
+    match result {
+        Ok(data) => HttpResponse::Ok().body(serde_json::to_string(&data).unwrap()),
+        Err(Error::NotFoundError) => HttpResponse::NotFound().body("User not found"),
+        Err(err) => {
+            println!("Error getting recommendations for group: {err}");
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ItemInfo {
+    item_id: Option<i64>,
+    url: Option<String>,
+}
+
+// Without a client, as on read only servers, only items in the database are found
+async fn resolve_item_id(
+    db: &Pool<SqliteConnectionManager>,
+    client: Option<&client::BandcampClient>,
+    query: &ItemInfo,
+) -> Result<i64, Error> {
+    match (query.item_id, &query.url, client) {
+        (Some(item_id), _, _) => {
+            let conn = db.get().context(DbPoolSnafu)?;
+            ensure!(items::item_exists(&conn, item_id)?, NotFoundSnafu);
+            Ok(item_id)
+        }
+        (None, Some(url), Some(client)) => items::fetch_item_id_for_url(db, client, url).await,
+        (None, Some(url), None) => {
+            let conn = db.get().context(DbPoolSnafu)?;
+            items::get_item_id_for_url(&conn, items::normalize_item_url(url))?
+                .context(NotFoundSnafu)
+        }
+        (None, None, _) => Err(Error::NotFoundError),
+    }
+}
+
+#[get("/api/get_item_status")]
+async fn get_item_status(
+    query: web::Query<ItemInfo>,
+    data: DataType,
+    client: ClientType,
+) -> HttpResponse {
+    let result = match resolve_item_id(data.get_ref(), Some(&client), &query).await {
+        Ok(item_id) => data
+            .get()
+            .context(DbPoolSnafu)
+            .and_then(|conn| progress_manager::add_item_target(&conn, item_id)),
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(target) => HttpResponse::Ok().body(serde_json::to_string(&target).unwrap()),
+        Err(Error::NotFoundError) => HttpResponse::NotFound().body("Item not found"),
+        Err(err) => {
+            println!("Error getting status for item: {err}");
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+#[get("/api/similar_items")]
+async fn similar_items(
+    query: web::Query<ItemInfo>,
+    data: DataType,
+    client: Option<ClientType>,
+) -> HttpResponse {
+    let client = client.as_ref().map(|client| client.get_ref());
+    let item_id = match resolve_item_id(data.get_ref(), client, &query).await {
+        Ok(item_id) => item_id,
+        Err(Error::NotFoundError) => return HttpResponse::NotFound().body("Item not found"),
+        Err(err) => {
+            println!("Error getting similar items: {err}");
+            return HttpResponse::InternalServerError().body("Internal server error");
+        }
+    };
+    let result = spawn_blocking(move || analyze::get_similar_items(data.get_ref(), item_id))
+        .await
+        .unwrap();
+    match result {
+        Ok(data) => HttpResponse::Ok().body(serde_json::to_string(&data).unwrap()),
+        Err(Error::NotFoundError) => HttpResponse::NotFound().body("Item not found"),
+        Err(err) => {
+            println!("Error getting similar items: {err}");
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JobsInfo {
+    state: Option<String>,
+    limit: Option<usize>,
+}
+
+#[get("/api/admin/jobs")]
+async fn get_jobs(
+    request: HttpRequest,
+    query: web::Query<JobsInfo>,
+    data: DataType,
+    admin: AdminType,
+) -> HttpResponse {
+    if !admin.authorized(&request) {
+        return HttpResponse::Unauthorized().body("Admin token required");
+    }
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    let result = data
+        .get()
+        .context(DbPoolSnafu)
+        .and_then(|conn| jobs::get_jobs(&conn, query.state.as_deref(), limit));
+    match result {
+        Ok(jobs) => HttpResponse::Ok().body(serde_json::to_string(&jobs).unwrap()),
+        Err(err) => {
+            println!("Error listing jobs: {err}");
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RequeueInfo {
+    kind: Option<types::JobKind>,
+    target_id: Option<i64>,
+}
+
+#[post("/api/admin/requeue")]
+async fn requeue_jobs(
+    request: HttpRequest,
+    query: web::Query<RequeueInfo>,
+    data: DataType,
+    admin: AdminType,
+) -> HttpResponse {
+    if !admin.authorized(&request) {
+        return HttpResponse::Unauthorized().body("Admin token required");
+    }
+    let result = data
+        .get()
+        .context(DbPoolSnafu)
+        .and_then(|conn| jobs::requeue_failed(&conn, query.kind, query.target_id));
+    match result {
+        Ok(count) => HttpResponse::Ok().body(format!("Requeued {count} jobs")),
+        Err(err) => {
+            println!("Error requeueing jobs: {err}");
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportInfo {
+    table: ExportTable,
+    #[serde(default)]
+    format: ExportFormat,
+    #[serde(default)]
+    anonymize: bool,
+}
+
+// Passes everything written on to the response body, fails once the client is gone
+struct ChannelWriter(mpsc::Sender<web::Bytes>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(web::Bytes::copy_from_slice(buf))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[get("/api/admin/export")]
+async fn export_table(
+    request: HttpRequest,
+    query: web::Query<ExportInfo>,
+    data: DataType,
+    admin: AdminType,
+) -> HttpResponse {
+    if !admin.authorized(&request) {
+        return HttpResponse::Unauthorized().body("Admin token required");
+    }
+    let ExportInfo {
+        table,
+        format,
+        anonymize,
+    } = query.into_inner();
+    let (sender, receiver) = mpsc::channel(16);
+    spawn_blocking(move || {
+        let options = ExportOptions {
+            format,
+            anonymize,
+            salt: admin.export_salt.clone(),
+        };
+        let out = BufWriter::with_capacity(64 * 1024, ChannelWriter(sender));
+        // the response already started, so errors can only cut it short
+        let result = data
+            .get()
+            .context(DbPoolSnafu)
+            .and_then(|conn| export::export_table(&conn, table, &options, out));
+        if let Err(err) = result {
+            println!("Error exporting {}: {err}", table.name());
+        }
+    });
+    let body = stream::unfold(receiver, |mut receiver| async move {
+        let bytes = receiver.recv().await?;
+        Some((Ok::<_, Infallible>(bytes), receiver))
+    });
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(ContentDisposition::attachment(format!(
+            "{}.{}",
+            table.name(),
+            format.extension()
+        )))
+        .streaming(body)
+}
+
+#[get("/classless.css")]
+async fn get_classless() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType(mime::TEXT_CSS))
+        .body(include_str!("../web_src/classless.css"))
+}
+
+#[get("/index.html")]
+async fn get_index() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(include_str!("../web_src/index.html"))
+}
+
+#[get("/")]
+async fn get_root() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(include_str!("../web_src/index.html"))
+}
+
+// Builds the web server, without the workers that fetch what it asks for.
+// The admin endpoints only exist with an admin config
+pub fn server(
+    store: Store,
+    client: BandcampClient,
+    progress: broadcast::Sender<Target>,
+    read_only: bool,
+    admin: Option<AdminConfig>,
+    address: SocketAddr,
+) -> std::io::Result<Server> {
+    let data = web::Data::new(store);
+    let admin = admin.map(web::Data::new);
+    let cache = web::Data::new(analyze::RankingCache::default());
+    let client = web::Data::new(client);
+    let progress = web::Data::new(progress);
+    let server = HttpServer::new(move || {
+        let app = App::new()
+            .app_data(data.clone())
+            .app_data(cache.clone())
+            .app_data(progress.clone())
+            .service(get_recommendations)
+            .service(get_purchases)
+            .service(get_feed)
+            .service(get_atom_feed)
+            .service(get_group_recommendations)
+            .service(similar_items)
+            .service(get_classless)
+            .service(get_index)
+            .service(get_root);
+        let app = match &admin {
+            Some(admin) => app
+                .app_data(admin.clone())
+                .service(get_jobs)
+                .service(export_table),
+            None => app,
+        };
+        if read_only {
+            return app;
+        }
+        // everything that may fetch from bandcamp
+        let app = app
+            .app_data(client.clone())
+            .service(get_status)
+            .service(status_stream)
+            .service(get_user)
+            .service(get_group_status)
+            .service(dismiss)
+            .service(undismiss)
+            .service(get_item_status);
+        if admin.is_some() {
+            app.service(requeue_jobs)
+        } else {
+            app
+        }
+    })
+    .bind(address)?
+    .run();
+    Ok(server)
+}
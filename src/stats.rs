@@ -0,0 +1,69 @@
+use crate::{DbPrepareSnafu, DbReadSnafu, Error};
+use rusqlite::Connection;
+use serde::Serialize;
+use snafu::ResultExt;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Stats {
+    pub collectors: i64,
+    pub fetched_collectors: i64,
+    pub items: i64,
+    pub fetched_items: i64,
+    pub collects: i64,
+    pub collected_by: i64,
+    pub wishes: i64,
+    pub queued_jobs: i64,
+    pub failed_jobs: i64,
+    pub targets: i64,
+}
+
+impl Stats {
+    pub fn rows(&self) -> [(&'static str, i64); 10] {
+        [
+            ("collectors", self.collectors),
+            ("fetched collectors", self.fetched_collectors),
+            ("items", self.items),
+            ("fetched items", self.fetched_items),
+            ("collects", self.collects),
+            ("collected_by", self.collected_by),
+            ("wishes", self.wishes),
+            ("queued jobs", self.queued_jobs),
+            ("failed jobs", self.failed_jobs),
+            ("targets", self.targets),
+        ]
+    }
+}
+
+const SELECT_STATS: &str = r#"
+select
+    (select count(*) from collector),
+    (select count(*) from collector where last_updated > 0),
+    (select count(*) from item),
+    (select count(*) from item where last_updated > 0),
+    (select count(*) from collects),
+    (select count(*) from collected_by),
+    (select count(*) from wishes),
+    (select count(*) from job where state = 'queued'),
+    (select count(*) from job where state = 'failed'),
+    (select count(*) from collection_target)"#;
+
+pub fn get_stats(db: &Connection) -> Result<Stats, Error> {
+    let mut stmt = db.prepare_cached(SELECT_STATS).context(DbPrepareSnafu)?;
+    let result = stmt
+        .query_row([], |row| {
+            Ok(Stats {
+                collectors: row.get(0)?,
+                fetched_collectors: row.get(1)?,
+                items: row.get(2)?,
+                fetched_items: row.get(3)?,
+                collects: row.get(4)?,
+                collected_by: row.get(5)?,
+                wishes: row.get(6)?,
+                queued_jobs: row.get(7)?,
+                failed_jobs: row.get(8)?,
+                targets: row.get(9)?,
+            })
+        })
+        .context(DbReadSnafu)?;
+    Ok(result)
+}
@@ -0,0 +1,137 @@
+use crate::freshness::{register_functions, FreshnessPolicy};
+use crate::migrations::{self, check_version, Migration};
+use crate::{DbPoolSnafu, Error};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OpenFlags};
+use snafu::ResultExt;
+use std::ops::Deref;
+use std::path::Path;
+
+// Wal lets the web readers continue while a worker writes, and the busy timeout makes the
+// writers wait for each other instead of failing with "database is locked"
+const WRITER_PRAGMAS: &str = r#"
+pragma journal_mode = wal;
+pragma synchronous = normal;"#;
+
+const CONNECTION_PRAGMAS: &str = r#"
+pragma busy_timeout = 10000;
+pragma foreign_keys = on;"#;
+
+fn init_connection(
+    conn: &mut Connection,
+    policy: FreshnessPolicy,
+    read_only: bool,
+) -> rusqlite::Result<()> {
+    if !read_only {
+        conn.execute_batch(WRITER_PRAGMAS)?;
+    }
+    conn.execute_batch(CONNECTION_PRAGMAS)?;
+    register_functions(conn, policy)
+}
+
+// Sqlite connection pool with the schema and the sql functions all queries rely on
+#[derive(Clone)]
+pub struct Store {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl Store {
+    // Applies pending migrations, see migrate for checking them first
+    pub fn open(path: impl AsRef<Path>, policy: FreshnessPolicy) -> Result<Store, Error> {
+        let store = Store::open_unmigrated(path, policy)?;
+        for migration in store.migrate(false)? {
+            println!(
+                "Applied migration {} ({})",
+                migration.version, migration.name
+            );
+        }
+        Ok(store)
+    }
+
+    pub fn open_unmigrated(
+        path: impl AsRef<Path>,
+        policy: FreshnessPolicy,
+    ) -> Result<Store, Error> {
+        let manager = SqliteConnectionManager::file(path)
+            .with_init(move |conn| init_connection(conn, policy, false));
+        let pool = Pool::new(manager).context(DbPoolSnafu)?;
+        Ok(Store { pool })
+    }
+
+    // Every write fails, so the schema has to be up to date already
+    pub fn open_read_only(path: impl AsRef<Path>, policy: FreshnessPolicy) -> Result<Store, Error> {
+        let manager = SqliteConnectionManager::file(path)
+            .with_flags(
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )
+            .with_init(move |conn| init_connection(conn, policy, true));
+        let pool = Pool::new(manager).context(DbPoolSnafu)?;
+        let conn = pool.get().context(DbPoolSnafu)?;
+        check_version(&conn)?;
+        drop(conn);
+        Ok(Store { pool })
+    }
+
+    pub fn migrate(&self, dry_run: bool) -> Result<Vec<Migration>, Error> {
+        let mut conn = self.connection()?;
+        migrations::migrate(&mut conn, dry_run)
+    }
+
+    pub fn schema_version(&self) -> Result<i64, Error> {
+        let conn = self.connection()?;
+        migrations::schema_version(&conn)
+    }
+
+    pub fn connection(&self) -> Result<PooledConnection<SqliteConnectionManager>, Error> {
+        self.pool.get().context(DbPoolSnafu)
+    }
+}
+
+// Lets the store be passed wherever a pool is expected
+impl Deref for Store {
+    type Target = Pool<SqliteConnectionManager>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.pool
+    }
+}
+
+// Fresh database in the temp dir, unique per test process
+#[cfg(test)]
+pub fn test_pool(name: &str) -> Store {
+    let path = std::env::temp_dir().join(format!(
+        "bandcamp_recommendations_{}_{name}.db",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    Store::open(path, FreshnessPolicy::default()).expect("Unable to initialize database")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_use_wal_and_cascade_deletes() {
+        let store = test_pool("store");
+        let conn = store.connection().unwrap();
+        let mode: String = conn
+            .query_row("pragma journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+        conn.execute_batch(
+            "insert into collector values (1, 'a', 'A', null, 0);
+            insert into item values (1, 'album', 'A', '', 1, 'Band', null, 0, 0);
+            insert into collects values (1, 1);
+            delete from collector where fan_id = 1;",
+        )
+        .unwrap();
+        let left: i64 = conn
+            .query_row("select count(*) from collects", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(left, 0);
+    }
+}
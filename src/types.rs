@@ -1,8 +1,9 @@
 use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use rusqlite::Row;
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ItemType {
     #[serde(rename = "album")]
     Album,
@@ -29,6 +30,21 @@ impl FromSql for ItemType {
     }
 }
 
+impl FromStr for ItemType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "album" => Ok(ItemType::Album),
+            "track" => Ok(ItemType::Track),
+            "package" => Ok(ItemType::Package),
+            "lepledge" => Ok(ItemType::Lepledge),
+            "subscription" => Ok(ItemType::Subscription),
+            _ => Err(()),
+        }
+    }
+}
+
 impl ToSql for ItemType {
     fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
         Ok(ToSqlOutput::Borrowed(ValueRef::Text(match self {
@@ -41,6 +57,32 @@ impl ToSql for ItemType {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    Item,
+    Collector,
+}
+
+impl FromSql for JobKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(b"item") => Ok(JobKind::Item),
+            ValueRef::Text(b"collector") => Ok(JobKind::Collector),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+impl ToSql for JobKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Borrowed(ValueRef::Text(match self {
+            JobKind::Item => b"item",
+            JobKind::Collector => b"collector",
+        })))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Item {
     pub item_id: i64,
@@ -54,6 +96,16 @@ pub struct Item {
     pub token: Option<String>,
     pub also_collected_count: i64,
     pub score: Option<f64>,
+    pub explanation: Option<Explanation>,
+    // only present in collections, e.g. "19 Jul 2023 20:27:07 GMT"
+    #[serde(default, skip_serializing)]
+    pub purchased: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Explanation {
+    pub collectors: Vec<String>,
+    pub items: Vec<String>,
 }
 
 pub fn item_from_row(row: &Row) -> rusqlite::Result<Item> {
@@ -69,9 +121,48 @@ pub fn item_from_row(row: &Row) -> rusqlite::Result<Item> {
         token: row.get("token")?,
         also_collected_count: row.get("also_collected_count")?,
         score: None,
+        explanation: None,
+        purchased: None,
     })
 }
 
+#[derive(Serialize, Debug, Clone)]
+pub struct Purchase {
+    #[serde(flatten)]
+    pub item: Item,
+    // when bandcamp says it was bought, or when it was first seen otherwise
+    pub purchased_at: i64,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    pub removed: Option<i64>,
+}
+
+pub fn purchase_from_row(row: &Row) -> rusqlite::Result<Purchase> {
+    Ok(Purchase {
+        item: item_from_row(row)?,
+        purchased_at: row.get("purchased_at")?,
+        first_seen: row.get("first_seen")?,
+        last_seen: row.get("last_seen")?,
+        removed: row.get("removed")?,
+    })
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FeedEntry {
+    #[serde(flatten)]
+    pub item: Item,
+    // the most recent purchase among the neighbours
+    pub purchased_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecommendationPage {
+    pub total: usize,
+    pub offset: usize,
+    pub items: Vec<Item>,
+    pub wishlist: Option<Vec<Item>>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Collector {
     pub fan_id: i64,
@@ -87,6 +178,19 @@ pub struct Target {
     pub count_left: i64,
     pub count_total: i64,
     pub eta: i64,
+    pub eta_low: i64,
+    pub eta_high: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ItemTarget {
+    pub item_id: i64,
+    pub stage: i64,
+    pub count_left: i64,
+    pub count_total: i64,
+    pub eta: i64,
+    pub eta_low: i64,
+    pub eta_high: i64,
 }
 
 pub fn target_from_row(row: &Row) -> rusqlite::Result<Target> {
@@ -96,5 +200,33 @@ pub fn target_from_row(row: &Row) -> rusqlite::Result<Target> {
         count_left: row.get("count_left")?,
         count_total: row.get("count_total")?,
         eta: row.get("eta")?,
+        // only the point estimate is stored
+        eta_low: row.get("eta")?,
+        eta_high: row.get("eta")?,
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub kind: JobKind,
+    pub target_id: i64,
+    pub state: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub next_attempt: i64,
+    pub priority: i64,
+    pub requested_by: Option<i64>,
+}
+
+pub fn job_from_row(row: &Row) -> rusqlite::Result<Job> {
+    Ok(Job {
+        kind: row.get("kind")?,
+        target_id: row.get("target_id")?,
+        state: row.get("state")?,
+        attempts: row.get("attempts")?,
+        last_error: row.get("last_error")?,
+        next_attempt: row.get("next_attempt")?,
+        priority: row.get("priority")?,
+        requested_by: row.get("requested_by")?,
     })
 }
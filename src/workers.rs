@@ -0,0 +1,55 @@
+use crate::client::BandcampClient;
+use crate::store::Store;
+use crate::types::Target;
+use crate::{collectors, cooccurrence, items, progress_manager};
+use std::sync::atomic::AtomicBool;
+use tokio::spawn;
+use tokio::sync::broadcast::Sender;
+use tokio::task::JoinHandle;
+
+// Fetches queued jobs, or everything stale when crawling, until run_state is cleared
+pub fn spawn_workers(
+    store: &Store,
+    client: &BandcampClient,
+    crawl: bool,
+    progress: &Sender<Target>,
+    run_state: &'static AtomicBool,
+) -> Vec<JoinHandle<()>> {
+    let db_copy = store.clone();
+    let client_copy = client.clone();
+    let collection_worker = spawn(async move {
+        while let Err(res) =
+            collectors::collection_worker(&db_copy, &client_copy, crawl, run_state).await
+        {
+            println!("Error in collection_worker: {res}");
+        }
+    });
+    let db_copy = store.clone();
+    let client_copy = client.clone();
+    let item_worker = spawn(async move {
+        while let Err(res) = items::item_worker(&db_copy, &client_copy, crawl, run_state).await {
+            println!("Error in item_worker: {res}");
+        }
+    });
+    let db_copy = store.clone();
+    let cooccurrence_worker = spawn(async move {
+        while let Err(res) = cooccurrence::cooccurrence_worker(&db_copy, run_state).await {
+            println!("Error in cooccurrence_worker: {res}");
+        }
+    });
+    let db_copy = store.clone();
+    let sender_copy = progress.clone();
+    let progress_manager = spawn(async move {
+        while let Err(res) =
+            progress_manager::progress_manager(&db_copy, &sender_copy, run_state).await
+        {
+            println!("Error in progress_manager: {res}");
+        }
+    });
+    vec![
+        collection_worker,
+        item_worker,
+        cooccurrence_worker,
+        progress_manager,
+    ]
+}